@@ -0,0 +1,68 @@
+//! Compares relaying a connection with splice(2) against copying it through
+//! userspace, over loopback. Run with `cargo bench --bench bridge`.
+
+#[macro_use]
+extern crate log;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+/// The proxy is a binary, so the module under test is pulled in by path.
+#[path = "../src/utils/relay.rs"]
+mod relay;
+
+/// Bytes sent through the relay per iteration
+const TRANSFER: usize = 16 * 1024 * 1024;
+
+async fn connected_pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+    let (client, server) = tokio::join!(TcpStream::connect(listener.local_addr().unwrap()), listener.accept());
+    (client.unwrap(), server.unwrap().0)
+}
+
+/// Sends `TRANSFER` bytes from a client through the relay to a backend and
+/// waits until the backend has read all of them.
+async fn transfer(listener: &TcpListener, payload: &[u8], splice: bool) {
+    let (mut client, proxy_in) = connected_pair(listener).await;
+    let (proxy_out, mut backend) = connected_pair(listener).await;
+    let (reader, _) = proxy_in.into_split();
+    let (_, writer) = proxy_out.into_split();
+    let relayed = tokio::spawn(relay::relay(reader, writer, splice));
+
+    let send = async {
+        client.write_all(payload).await.unwrap();
+        client.shutdown().await.unwrap();
+    };
+    let receive = async {
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut received = 0;
+        loop {
+            match backend.read(&mut buf).await.unwrap() {
+                0 => break received,
+                read => received += read,
+            }
+        }
+    };
+    let (_, received) = tokio::join!(send, receive);
+    assert_eq!(received, TRANSFER);
+    assert_eq!(relayed.await.unwrap().0, TRANSFER as u64);
+}
+
+fn bridge(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let payload = vec![0x5a; TRANSFER];
+
+    let mut group = c.benchmark_group("bridge");
+    group.throughput(Throughput::Bytes(TRANSFER as u64));
+    group.sample_size(20);
+    group.bench_function("copy", |b| b.iter(|| runtime.block_on(transfer(&listener, &payload, false))));
+    if relay::splice_available() {
+        group.bench_function("splice", |b| b.iter(|| runtime.block_on(transfer(&listener, &payload, true))));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bridge);
+criterion_main!(benches);
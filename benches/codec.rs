@@ -0,0 +1,161 @@
+//! Compares the buffer-based codec with the async byte-at-a-time framing it
+//! replaced. Run with `cargo bench --bench codec`.
+
+#[macro_use]
+extern crate anyhow;
+
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use tokio::runtime::Builder;
+
+/// The proxy is a binary, so the modules under test are pulled in by path.
+/// Their unit tests come along without a test harness to run them.
+#[path = "../src/utils"]
+#[allow(dead_code, unused_imports)]
+pub mod utils {
+    pub mod codec;
+    pub mod packet;
+}
+
+use utils::codec;
+use utils::packet::{PacketWriter, StatusResponse};
+
+/// The framing used before the codec, kept to compare against.
+mod legacy {
+    use anyhow::Result;
+    use std::io::Cursor;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+    pub async fn read_var_int<T>(stream: &mut T) -> Result<i32>
+    where
+        T: AsyncRead + std::marker::Unpin,
+    {
+        let mut num_read: i32 = 0;
+        let mut result: i32 = 0;
+        loop {
+            let read = stream.read_u8().await? as i32;
+            let value = read & 0b0111_1111;
+            result |= value << (7 * num_read);
+            num_read += 1;
+            if num_read > 5 {
+                return Err(anyhow!("VarInt too big!"));
+            }
+            if (read & 0b1000_0000) == 0 {
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    pub async fn write_var_int<T>(stream: &mut T, mut value: i32) -> Result<()>
+    where
+        T: AsyncWrite + std::marker::Unpin,
+    {
+        loop {
+            let mut temp: i16 = (value & 0b0111_1111) as i16;
+            value >>= 7;
+            if value != 0 {
+                temp |= 0b1000_0000;
+            }
+            stream.write_i8(temp as i8).await?;
+            if value == 0 {
+                break Ok(());
+            }
+        }
+    }
+
+    pub async fn write_string<T>(stream: &mut T, string: &str) -> Result<()>
+    where
+        T: AsyncWrite + std::marker::Unpin,
+    {
+        let mut temp: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        write_var_int(&mut temp, 0).await?;
+        write_var_int(&mut temp, string.len() as i32).await?;
+        temp.write_all(string.as_bytes()).await?;
+        let temp = temp.into_inner();
+        write_var_int(stream, temp.len() as i32).await?;
+        stream.write_all(&temp).await?;
+        Ok(())
+    }
+}
+
+/// Non-negative only: the legacy writer never terminates on negative values.
+const VALUES: [i32; 6] = [0, 1, 300, 25565, 2_097_151, i32::MAX];
+
+fn status_json(favicon_bytes: usize) -> String {
+    format!(
+        r#"{{"version":{{"name":"1.21.4","protocol":769}},"players":{{"max":100,"online":7}},"description":{{"text":"A Minecraft Server"}},"favicon":"data:image/png;base64,{}"}}"#,
+        "A".repeat(favicon_bytes)
+    )
+}
+
+fn var_int(c: &mut Criterion) {
+    let runtime = Builder::new_current_thread().build().unwrap();
+    let mut encoded = Vec::new();
+    for value in VALUES {
+        codec::put_var_int(&mut encoded, value);
+    }
+
+    let mut group = c.benchmark_group("var_int");
+    let mut out = Vec::with_capacity(64);
+    group.bench_function("write/legacy", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                out.clear();
+                for value in VALUES {
+                    legacy::write_var_int(&mut out, black_box(value)).await.unwrap();
+                }
+            })
+        })
+    });
+    let mut out = BytesMut::with_capacity(64);
+    group.bench_function("write/codec", |b| {
+        b.iter(|| {
+            out.clear();
+            for value in VALUES {
+                codec::put_var_int(&mut out, black_box(value));
+            }
+        })
+    });
+    group.bench_function("read/legacy", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let mut input = black_box(&encoded[..]);
+                for _ in VALUES {
+                    legacy::read_var_int(&mut input).await.unwrap();
+                }
+            })
+        })
+    });
+    group.bench_function("read/codec", |b| {
+        b.iter(|| {
+            let mut input = black_box(&encoded[..]);
+            for _ in VALUES {
+                codec::get_var_int(&mut input).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+fn status_response(c: &mut Criterion) {
+    let runtime = Builder::new_current_thread().build().unwrap();
+    for (name, favicon_bytes) in [("plain", 0), ("favicon", 6000)] {
+        let json = status_json(favicon_bytes);
+        let packet = StatusResponse { json: json.clone() };
+
+        let mut group = c.benchmark_group(format!("status_response/{}", name));
+        group.bench_function("legacy", |b| {
+            let mut sink = tokio::io::sink();
+            b.iter(|| runtime.block_on(legacy::write_string(&mut sink, black_box(&json))).unwrap())
+        });
+        group.bench_function("codec", |b| {
+            let mut writer = PacketWriter::new(tokio::io::sink());
+            b.iter(|| runtime.block_on(writer.send(black_box(&packet))).unwrap())
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, var_int, status_response);
+criterion_main!(benches);
@@ -0,0 +1,67 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(name = "madproxy", version, about = "Reverse proxy for Minecraft Java servers")]
+pub struct Cli {
+    /// Configuration file
+    #[arg(short, long, env = "MADPROXY_CONFIG", default_value = "./config.yml")]
+    pub config: PathBuf,
+
+    /// Overrides `listen_addr`
+    #[arg(long)]
+    pub listen_addr: Option<String>,
+
+    /// Overrides `auto_start`
+    #[arg(long)]
+    pub auto_start: Option<bool>,
+
+    /// Overrides `panel_link`
+    #[arg(long)]
+    pub panel_link: Option<String>,
+
+    /// Overrides any configuration field, e.g. `--set hold_login.enabled=true`
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub set: Vec<String>,
+
+    /// Print the merged configuration with secrets redacted and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Convert a config or server list between YAML, TOML and JSON, picking
+    /// formats by file extension
+    Convert {
+        input: PathBuf,
+        output: PathBuf,
+    },
+}
+
+impl Cli {
+    /// Command line overrides as dotted field paths, applied after the
+    /// environment.
+    pub fn overrides(&self) -> Vec<(String, String)> {
+        let mut overrides = Vec::new();
+        if let Some(listen_addr) = &self.listen_addr {
+            overrides.push(("listen_addr".to_string(), listen_addr.clone()));
+        }
+        if let Some(auto_start) = self.auto_start {
+            overrides.push(("auto_start".to_string(), auto_start.to_string()));
+        }
+        if let Some(panel_link) = &self.panel_link {
+            overrides.push(("panel_link".to_string(), panel_link.clone()));
+        }
+        for set in &self.set {
+            match set.split_once('=') {
+                Some((key, value)) => overrides.push((key.trim().to_string(), value.to_string())),
+                None => warn!("Ignoring --set {}, expected KEY=VALUE", set),
+            }
+        }
+        overrides
+    }
+}
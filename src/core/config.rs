@@ -1,8 +1,18 @@
 use serde::{Deserialize, Serialize};
 use std::default::Default;
-use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use crate::core::controller::default_docker_socket;
+use crate::core::format;
+use crate::core::rules::Rule;
+use crate::core::secret::Secret;
+use crate::core::servers::{ServerEntry, ServerMessages};
+use crate::utils::{chat, favicon};
+use ipnet::IpNet;
+use rand::Rng;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use serde_json::json;
+use serde_yaml::{Mapping, Value};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MOTD {
@@ -10,6 +20,146 @@ pub struct MOTD {
     protocol_name: String,
 }
 
+/// Shown when a set has no entry to offer
+static EMPTY_MOTD: MOTD = MOTD { text: String::new(), protocol_name: String::new() };
+
+/// A MOTD slot: either a single MOTD or a set of them to pick from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MotdSlot {
+    Set(MotdSet),
+    Single(MOTD),
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MotdMode {
+    /// A random entry on every ping
+    #[default]
+    Random,
+    /// The next entry every `interval_secs`
+    Rotate,
+    /// The first entry whose `from`..`until` window (UTC) holds the current time
+    Schedule,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MotdSet {
+    #[serde(default)]
+    pub mode: MotdMode,
+    #[serde(default = "default_rotate_interval")]
+    pub interval_secs: u64,
+    pub entries: Vec<MotdEntry>,
+}
+
+fn default_rotate_interval() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MotdEntry {
+    #[serde(flatten)]
+    motd: MOTD,
+    /// Start of the time window as `HH:MM` UTC, used by `schedule`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    from: Option<String>,
+    /// End of the time window as `HH:MM` UTC, may wrap past midnight
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    until: Option<String>,
+}
+
+impl MotdEntry {
+    /// Whether the entry's window holds `minute` of the day. Entries without a
+    /// window always match.
+    fn covers(&self, minute: u32) -> bool {
+        window_covers(self.from.as_deref(), self.until.as_deref(), minute)
+    }
+}
+
+impl MotdSlot {
+    /// The MOTD to show right now.
+    pub fn pick(&self) -> &MOTD {
+        self.pick_at(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs())
+    }
+
+    /// The MOTD to show `now` seconds after the epoch.
+    fn pick_at(&self, now: u64) -> &MOTD {
+        let set = match self {
+            MotdSlot::Single(motd) => return motd,
+            MotdSlot::Set(set) => set,
+        };
+        let entry = match set.mode {
+            _ if set.entries.is_empty() => None,
+            MotdMode::Random => set.entries.get(rand::thread_rng().gen_range(0..set.entries.len())),
+            MotdMode::Rotate => {
+                let slot = now / set.interval_secs.max(1);
+                set.entries.get((slot % set.entries.len() as u64) as usize)
+            }
+            MotdMode::Schedule => {
+                let minute = ((now % 86_400) / 60) as u32;
+                set.entries.iter().find(|entry| entry.covers(minute))
+            }
+        };
+        entry.map(|entry| &entry.motd).unwrap_or(&EMPTY_MOTD)
+    }
+
+    /// Describes what is wrong with the slot at `at`, if anything.
+    pub fn problems(&self, at: &str) -> Vec<String> {
+        let set = match self {
+            MotdSlot::Single(_) => return Vec::new(),
+            MotdSlot::Set(set) => set,
+        };
+        let mut problems = Vec::new();
+        if set.entries.is_empty() {
+            problems.push(format!("{}.entries: at least one entry is needed", at));
+        }
+        if set.mode == MotdMode::Rotate && set.interval_secs == 0 {
+            problems.push(format!("{}.interval_secs: must be greater than 0", at));
+        }
+        for (index, entry) in set.entries.iter().enumerate() {
+            for (field, value) in [("from", &entry.from), ("until", &entry.until)] {
+                if let Some(value) = value {
+                    if parse_time_of_day(value).is_none() {
+                        problems.push(format!("{}.entries[{}].{}: {:?} is not a HH:MM time", at, index, field, value));
+                    }
+                }
+            }
+        }
+        problems
+    }
+}
+
+impl From<MOTD> for MotdSlot {
+    fn from(motd: MOTD) -> Self {
+        MotdSlot::Single(motd)
+    }
+}
+
+/// Minutes since midnight for a `HH:MM` time.
+pub fn parse_time_of_day(value: &str) -> Option<u32> {
+    let (hours, minutes) = value.trim().split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+/// Minutes since midnight UTC.
+pub fn minute_of_day() -> u32 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    ((now % 86_400) / 60) as u32
+}
+
+/// Whether the `HH:MM` window from `from` until `until` holds `minute`. A
+/// missing bound is open, and a window may wrap past midnight.
+pub fn window_covers(from: Option<&str>, until: Option<&str>, minute: u32) -> bool {
+    let from = from.and_then(parse_time_of_day).unwrap_or(0);
+    let until = until.and_then(parse_time_of_day).unwrap_or(24 * 60);
+    if from <= until {
+        (from..until).contains(&minute)
+    } else {
+        minute >= from || minute < until
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Server {
     pub(crate) ip: String,
@@ -17,123 +167,434 @@ pub struct Server {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct UnknownHost {
     kick_message: String,
-    motd: MOTD
+    pub motd: MotdSlot,
+}
+
+impl Default for UnknownHost {
+    fn default() -> Self {
+        Self {
+            kick_message: "§bRust Minecraft Proxy\n\n§cInvalid Address".to_string(),
+            motd: MOTD { text: "§cUnknown host!\n§7Please use a valid address to connect.".to_string(), protocol_name: "§crust-minecraft-proxy".to_string(),  }.into()
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct OfflineServer {
     kick_message: String,
     starting_message: String,
-    motd: MOTD
+    pub motd: MotdSlot,
+    starting_motd: MOTD,
+    suspended_message: String,
+    suspended_motd: MOTD,
+    installing_message: String,
+    installing_motd: MOTD,
+    maintenance_message: String,
+    maintenance_motd: MOTD,
+    /// Server list icon for every known server, unless it sets its own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub favicon: Option<PathBuf>,
+    max_players: u32,
+}
+
+impl Default for OfflineServer {
+    fn default() -> Self {
+        Self {
+            kick_message: "§bRust Minecraft Proxy\n\n§cServer is offline".to_string(),
+            starting_message: "§bRust Minecraft Proxy\n\n§eServer is starting...".to_string(),
+            motd: MOTD { text: "§cServer is offline!\n§7Please try again later.".to_string(), protocol_name: "§cServer Offline".to_string() }.into(),
+            starting_motd: MOTD { text: "§eServer is starting...\n§7Starting for {elapsed}s, please wait.".to_string(), protocol_name: "§eStarting".to_string() },
+            suspended_message: "§bRust Minecraft Proxy\n\n§cThis server is suspended".to_string(),
+            suspended_motd: MOTD { text: "§cServer suspended\n§7Contact an administrator.".to_string(), protocol_name: "§cSuspended".to_string() },
+            installing_message: "§bRust Minecraft Proxy\n\n§eServer is still being installed".to_string(),
+            installing_motd: MOTD { text: "§eServer is installing...\n§7Please try again in a few minutes.".to_string(), protocol_name: "§eInstalling".to_string() },
+            maintenance_message: "§bRust Minecraft Proxy\n\n§6Server is under maintenance".to_string(),
+            maintenance_motd: MOTD { text: "§6Server is under maintenance\n§7Please try again later.".to_string(), protocol_name: "§6Maintenance".to_string() },
+            favicon: None,
+            max_players: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HoldLogin {
+    pub enabled: bool,
+    pub timeout_secs: u64,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for HoldLogin {
+    fn default() -> Self {
+        // The vanilla client gives up on the login screen after 30 seconds.
+        Self {
+            enabled: false,
+            timeout_secs: 25,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IdleStop {
+    pub enabled: bool,
+    pub timeout_secs: u64,
+    pub min_uptime_secs: u64,
+    /// Send `kill` if the server is still up this long after `stop`
+    pub kill_after_secs: Option<u64>,
+    pub check_interval_secs: u64,
+}
+
+impl Default for IdleStop {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout_secs: 600,
+            min_uptime_secs: 300,
+            kill_after_secs: None,
+            check_interval_secs: 15,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DockerDiscovery {
+    pub enabled: bool,
+    pub socket: PathBuf,
+    /// Network whose address is used to reach containers, defaults to the
+    /// first one with an address
+    pub network: Option<String>,
+}
+
+impl Default for DockerDiscovery {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            socket: default_docker_socket(),
+            network: None,
+        }
+    }
 }
 
+/// Accepting client addresses forwarded by TCPShield edges.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TcpShieldSettings {
+    pub enabled: bool,
+    /// The edge's public key, PEM or base64 DER
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_key_file: Option<PathBuf>,
+    /// How far an envelope's timestamp may be from the local clock
+    pub max_clock_skew_secs: u64,
+    /// Addresses allowed to send envelopes, required when enabled
+    pub trusted_sources: Vec<IpNet>,
+}
+
+impl Default for TcpShieldSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            public_key: None,
+            public_key_file: None,
+            max_clock_skew_secs: 5,
+            trusted_sources: Vec::new(),
+        }
+    }
+}
+
+impl TcpShieldSettings {
+    /// The configured key, read from `public_key_file` if not inline.
+    pub fn public_key(&self) -> Result<String> {
+        if let Some(key) = self.public_key.as_ref().filter(|k| !k.trim().is_empty()) {
+            return Ok(key.clone());
+        }
+        match &self.public_key_file {
+            Some(path) => std::fs::read_to_string(path).map_err(|e| anyhow!("cannot read {}: {}", path.display(), e)),
+            None => Err(anyhow!("tcpshield is enabled but neither public_key nor public_key_file is set")),
+        }
+    }
+}
+
+/// Fields missing from `config.yml` take their value from `Config::default`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub listen_addr: String,
     pub unknown_host: UnknownHost,
     pub offline_server: OfflineServer,
+    /// Replaces the description and version name running servers report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub online_motd: Option<MotdSlot>,
+    /// Kick for clients outside a server's `versions`
+    pub unsupported_version_message: String,
+    /// Checked in order before looking the hostname up
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
     pub auto_start: bool,
     pub panel_link: String,
-    pub api_key: String,
+    #[serde(skip_serializing_if = "Secret::is_empty")]
+    pub api_key: Secret,
+    /// Name of an environment variable holding the API key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key_env: Option<String>,
+    /// File whose contents are the API key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key_file: Option<PathBuf>,
+    pub start_cooldown_secs: u64,
+    pub start_timeout_secs: u64,
+    pub hold_login: HoldLogin,
+    pub idle_stop: IdleStop,
+    pub docker: DockerDiscovery,
+    pub tcpshield: TcpShieldSettings,
+    /// Bridge connections with splice(2) on Linux, keeping the bytes in the
+    /// kernel
+    pub splice: bool,
+    /// Server registry, in any format `config.yml` can be written in
+    pub servers_file: PathBuf,
+    /// Directory with one server per file, used instead of `servers_file`
+    /// when set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub servers_dir: Option<PathBuf>,
+    /// Servers defined inline, used instead of `servers_file` when present
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub servers: Option<Vec<ServerEntry>>,
 }
 
 impl Default for Config {
     fn default() -> Self {
-        let unknown_host = UnknownHost {
-            kick_message: "§bRust Minecraft Proxy\n\n§cInvalid Address".to_string(),
-            motd: MOTD { text: "§cUnknown host!\n§7Please use a valid address to connect.".to_string(), protocol_name: "§crust-minecraft-proxy".to_string(),  }
-        };
-
-        let offline_server = OfflineServer {
-            kick_message: "§bRust Minecraft Proxy\n\n§cServer is offline".to_string(),
-            starting_message: "§bRust Minecraft Proxy\n\n§eServer is starting...".to_string(),
-            motd: MOTD { text: "§cServer is offline!\n§7Please try again later.".to_string(), protocol_name: "§cServer Offline".to_string() }
-        };
-
         Self {
             listen_addr: "0.0.0.0:25565".to_string(),
-            unknown_host,
-            offline_server,
+            unknown_host: UnknownHost::default(),
+            offline_server: OfflineServer::default(),
+            online_motd: None,
+            rules: Vec::new(),
+            unsupported_version_message: "§bRust Minecraft Proxy\n\n§cThis server supports {supported_versions}\n§7You are on {client_version}".to_string(),
             auto_start: true,
             panel_link: "https://your.panel.link".to_string(),
-            api_key: "your_api_key".to_string(),
+            api_key: Secret::default(),
+            api_key_env: None,
+            api_key_file: None,
+            start_cooldown_secs: 30,
+            start_timeout_secs: 180,
+            hold_login: HoldLogin::default(),
+            idle_stop: IdleStop::default(),
+            docker: DockerDiscovery::default(),
+            tcpshield: TcpShieldSettings::default(),
+            splice: false,
+            servers_file: PathBuf::from("servers.json"),
+            servers_dir: None,
+            servers: None,
         }
     }
 }
 
 impl Config {
-    pub fn load_or_init(path: &Path) -> Config {
-        if path.exists() {
-            serde_yaml::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    /// Builds the configuration from, in increasing precedence, the defaults,
+    /// the file at `path` (created with the defaults if missing) and
+    /// `overrides`, given as dotted field paths such as `hold_login.enabled`.
+    pub fn load_layered(path: &Path, overrides: &[(String, String)]) -> Result<Config> {
+        let config = if path.exists() {
+            format::read_file(path)?
         } else {
             info!("Configuration file does not exist. Use defaults.");
             let default = Config::default();
             trace!("Default configuration: {:?}", default);
-            let string = serde_yaml::to_string(&default).unwrap();
-            fs::write(path, &string).unwrap();
+            format::write_file(path, &default)?;
             default
+        };
+        if overrides.is_empty() {
+            return Ok(config);
         }
+
+        let mut value = serde_yaml::to_value(&config)?;
+        for (key, raw) in overrides {
+            debug!("Overriding {}", key);
+            let typed = set_path(&mut value, key, raw);
+            // A string or secret field that is missing from the file, such as
+            // `api_key=123456`, reads as a number. Keep the raw text if the
+            // typed value is what breaks the configuration.
+            if !typed.is_string() && serde_yaml::from_value::<Config>(value.clone()).is_err() {
+                let mut raw_value = value.clone();
+                set_path_value(&mut raw_value, key, Value::String(raw.clone()));
+                if serde_yaml::from_value::<Config>(raw_value.clone()).is_ok() {
+                    value = raw_value;
+                }
+            }
+        }
+        serde_yaml::from_value(value).map_err(|e| anyhow!("environment or command line override: {}", e))
+    }
+
+    /// Collects `MADPROXY_*` environment variables as overrides. Nested fields
+    /// are separated by a double underscore, so `MADPROXY_HOLD_LOGIN__ENABLED`
+    /// sets `hold_login.enabled`.
+    pub fn env_overrides(vars: impl Iterator<Item = (String, String)>) -> Vec<(String, String)> {
+        let mut overrides: Vec<(String, String)> = vars
+            .filter_map(|(name, value)| {
+                let key = name.strip_prefix(ENV_PREFIX)?;
+                if RESERVED_ENV.contains(&key) {
+                    return None;
+                }
+                Some((key.to_lowercase().replace("__", "."), value))
+            })
+            .collect();
+        // Parents before children, so `A=...` cannot clobber a later `A__B=...`
+        overrides.sort();
+        overrides
+    }
+
+    /// A copy that is safe to print.
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        config.api_key = config.api_key.redacted();
+        for server in config.servers.iter_mut().flatten() {
+            if let Some(power) = &mut server.power {
+                power.redact();
+            }
+        }
+        config
     }
 
     pub fn get_listen_addr(&self) -> String {
         self.listen_addr.clone()
     }
 
-    pub fn get_unknown_host_kick_msg(&self) -> String {
-        let mut message: String = "{\"text\":\"".to_owned();
-        message.push_str(&self.unknown_host.kick_message);
-        message.push_str("\"}");
-        message
-    }
-
-    pub fn get_unknown_host_motd(&self) -> String {
-        json!({
-            "version": {
-                "name": &self.unknown_host.motd.protocol_name,
-                "protocol": -1
-            },
-            "players": {
-                "max": 0,
-                "online": 0,
-                "sample": []
-            },
-            "description": {
-                "text": &self.unknown_host.motd.text
-            }
-        }).to_string()
-    }
-
-    pub fn get_offline_server_kick_msg(&self) -> String {
-        let mut message: String = "{\"text\":\"".to_owned();
-        message.push_str(&self.offline_server.kick_message);
-        message.push_str("\"}");
-        message
-    }
-
-    pub fn get_offline_server_starting_msg(&self) -> String {
-        let mut message: String = "{\"text\":\"".to_owned();
-        message.push_str(&self.offline_server.starting_message);
-        message.push_str("\"}");
-        message
-    }
-
-    pub async fn get_offline_server_motd_not_starting(&self, _server_id: &str) -> String {
-        json!({
-            "version": {
-                "name": &self.offline_server.motd.protocol_name,
-                "protocol": -1
-            },
-            "players": {
-                "max": 0,
-                "online": 0,
-                "sample": []
-            },
-            "description": {
-                "text": &self.offline_server.motd.text
+    pub fn get_unknown_host_kick_msg(&self, context: &chat::Context) -> String {
+        kick_json(&self.unknown_host.kick_message, context)
+    }
+
+    pub fn get_unknown_host_motd(&self, context: &chat::Context) -> String {
+        let motd = self.unknown_host.motd.pick();
+        status_json(&motd.text, &motd.protocol_name, -1, 0, None, context)
+    }
+
+    pub fn get_offline_server_kick_msg(&self, server: &ServerEntry, context: &chat::Context) -> String {
+        let message = override_of(server, |m| m.kick_message.as_deref());
+        kick_json(message.unwrap_or(&self.offline_server.kick_message), context)
+    }
+
+    /// Disconnect reason when the backend a rule routes to is down. There is no
+    /// server entry to start, so this is the plain offline message.
+    pub fn get_offline_route_kick_msg(&self, context: &chat::Context) -> String {
+        kick_json(&self.offline_server.kick_message, context)
+    }
+
+    pub fn get_offline_route_motd(&self, context: &chat::Context) -> String {
+        let motd = self.offline_server.motd.pick();
+        status_json(&motd.text, &motd.protocol_name, -1, 0, None, context)
+    }
+
+    pub fn get_offline_server_starting_msg(&self, server: &ServerEntry, context: &chat::Context) -> String {
+        let message = override_of(server, |m| m.starting_message.as_deref());
+        kick_json(message.unwrap_or(&self.offline_server.starting_message), context)
+    }
+
+    pub fn get_offline_server_motd_not_starting(&self, server: &ServerEntry, context: &chat::Context) -> String {
+        let motd = self.offline_server.motd.pick();
+        let text = override_of(server, |m| m.motd.as_deref()).unwrap_or(&motd.text);
+        let version_name = override_of(server, |m| m.version_name.as_deref()).unwrap_or(&motd.protocol_name);
+        self.server_status(server, text, version_name, context)
+    }
+
+    pub fn get_offline_server_motd_starting(&self, server: &ServerEntry, context: &chat::Context) -> String {
+        let motd = &self.offline_server.starting_motd;
+        self.server_status(server, &motd.text, &motd.protocol_name, context)
+    }
+
+    pub fn get_suspended_msg(&self, context: &chat::Context) -> String {
+        kick_json(&self.offline_server.suspended_message, context)
+    }
+
+    pub fn get_suspended_motd(&self, server: &ServerEntry, context: &chat::Context) -> String {
+        let motd = &self.offline_server.suspended_motd;
+        self.server_status(server, &motd.text, &motd.protocol_name, context)
+    }
+
+    pub fn get_installing_msg(&self, context: &chat::Context) -> String {
+        kick_json(&self.offline_server.installing_message, context)
+    }
+
+    pub fn get_installing_motd(&self, server: &ServerEntry, context: &chat::Context) -> String {
+        let motd = &self.offline_server.installing_motd;
+        self.server_status(server, &motd.text, &motd.protocol_name, context)
+    }
+
+    pub fn get_maintenance_msg(&self, server: &ServerEntry, context: &chat::Context) -> String {
+        let message = override_of(server, |m| m.maintenance_message.as_deref());
+        kick_json(message.unwrap_or(&self.offline_server.maintenance_message), context)
+    }
+
+    pub fn get_unsupported_version_msg(&self, server: &ServerEntry, context: &chat::Context) -> String {
+        let message = override_of(server, |m| m.unsupported_version_message.as_deref());
+        kick_json(message.unwrap_or(&self.unsupported_version_message), context)
+    }
+
+    pub fn get_rule_kick_msg(&self, message: &str, context: &chat::Context) -> String {
+        kick_json(message, context)
+    }
+
+    pub fn get_rule_motd(&self, motd: &MotdSlot, context: &chat::Context) -> String {
+        let motd = motd.pick();
+        status_json(&motd.text, &motd.protocol_name, -1, 0, None, context)
+    }
+
+    pub fn get_maintenance_motd(&self, server: &ServerEntry, context: &chat::Context) -> String {
+        let motd = &self.offline_server.maintenance_motd;
+        self.server_status(server, &motd.text, &motd.protocol_name, context)
+    }
+
+    /// Rewrites the status JSON a running backend answered with, swapping in
+    /// the `online_motd`. The backend's player counts are available as
+    /// placeholders. Returns `None` if no override is configured.
+    pub fn get_online_status(&self, backend_status: &str, context: &chat::Context) -> Option<String> {
+        let motd = self.online_motd.as_ref()?.pick();
+        let mut status: serde_json::Value = match serde_json::from_str(backend_status) {
+            Ok(status) => status,
+            Err(e) => {
+                warn!("Backend sent an unreadable status, passing it through: {}", e);
+                return None;
             }
-        }).to_string()
+        };
+        let mut context = context.clone();
+        if let Some(online) = status["players"]["online"].as_i64() {
+            context = context.with("players_online", online);
+        }
+        if let Some(max) = status["players"]["max"].as_i64() {
+            context = context.with("max_players", max);
+        }
+        status["description"] = chat::component(&motd.text, &context);
+        if !motd.protocol_name.is_empty() && status["version"].is_object() {
+            status["version"]["name"] = json!(chat::legacy(&motd.protocol_name, &context));
+        }
+        Some(status.to_string())
+    }
+
+    /// Player limit shown in the server list for `server`.
+    pub fn max_players(&self, server: &ServerEntry) -> u32 {
+        override_of(server, |m| m.max_players.as_ref())
+            .copied()
+            .unwrap_or(self.offline_server.max_players)
+    }
+
+    /// Status response for `server`, carrying its favicon and player limit.
+    fn server_status(&self, server: &ServerEntry, text: &str, version_name: &str, context: &chat::Context) -> String {
+        let favicon = override_of(server, |m| m.favicon.as_ref()).or(self.offline_server.favicon.as_ref());
+        let favicon = favicon.and_then(|path| {
+            favicon::load(path)
+                .map_err(|e| warn!("Cannot load favicon for {}: {}", server.id, e))
+                .ok()
+        });
+        let protocol = server.versions.as_ref().map_or(-1, |v| v.advertised(context.protocol()));
+        status_json(text, version_name, protocol, self.max_players(server), favicon, context)
     }
 
     pub fn auto_start(&self) -> bool {
@@ -144,7 +605,243 @@ impl Config {
         self.panel_link.clone()
     }
 
-    pub fn api_key(&self) -> String {
-        self.api_key.clone()
+    /// Resolves the global API key from `api_key`, `api_key_env` or
+    /// `api_key_file`, in that order.
+    pub fn api_key(&self) -> Result<Secret> {
+        Secret::resolve(Some(&self.api_key), self.api_key_env.as_deref(), self.api_key_file.as_deref())?
+            .ok_or_else(|| anyhow!("no API key configured, set api_key, api_key_env or api_key_file"))
+    }
+
+    pub fn start_cooldown(&self) -> Duration {
+        Duration::from_secs(self.start_cooldown_secs)
+    }
+
+    pub fn start_timeout(&self) -> Duration {
+        Duration::from_secs(self.start_timeout_secs)
+    }
+
+    pub fn hold_login(&self) -> &HoldLogin {
+        &self.hold_login
+    }
+
+    pub fn idle_stop(&self) -> &IdleStop {
+        &self.idle_stop
+    }
+}
+
+const ENV_PREFIX: &str = "MADPROXY_";
+/// Variables read by the command line parser rather than mapped onto fields
+const RESERVED_ENV: &[&str] = &["CONFIG"];
+
+/// Sets a dotted path to `raw` and returns the value it was given. Fields that
+/// currently hold a string keep the raw text, anything else is parsed as YAML
+/// so numbers, booleans and lists come out typed.
+fn set_path(root: &mut Value, path: &str, raw: &str) -> Value {
+    let current = walk_path(root, path);
+    *current = match current {
+        Value::String(_) => Value::String(raw.to_string()),
+        _ => serde_yaml::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string())),
+    };
+    current.clone()
+}
+
+fn set_path_value(root: &mut Value, path: &str, value: Value) {
+    *walk_path(root, path) = value;
+}
+
+/// The value at a dotted path, creating missing mappings on the way.
+fn walk_path<'a>(root: &'a mut Value, path: &str) -> &'a mut Value {
+    let mut current = root;
+    for segment in path.split('.') {
+        if !current.is_mapping() {
+            *current = Value::Mapping(Mapping::new());
+        }
+        let map = current.as_mapping_mut().unwrap();
+        let key = Value::String(segment.to_string());
+        if !map.contains_key(&key) {
+            map.insert(key.clone(), Value::Null);
+        }
+        current = map.get_mut(&key).unwrap();
+    }
+    current
+}
+
+/// Disconnect reason for the client in `context`.
+fn kick_json(message: &str, context: &chat::Context) -> String {
+    chat::component(message, context).to_string()
+}
+
+/// Status response showing `text` as the description. The version name only
+/// takes legacy codes, so it is flattened to those.
+fn status_json(
+    text: &str,
+    version_name: &str,
+    protocol: i32,
+    max_players: u32,
+    favicon: Option<String>,
+    context: &chat::Context,
+) -> String {
+    let mut status = json!({
+        "version": {
+            "name": chat::legacy(version_name, context),
+            "protocol": protocol
+        },
+        "players": {
+            "max": max_players,
+            "online": 0,
+            "sample": []
+        },
+        "description": chat::component(text, context)
+    });
+    if let Some(favicon) = favicon {
+        status["favicon"] = json!(favicon);
+    }
+    status.to_string()
+}
+
+/// The per-server replacement for a message, if `server` sets one.
+fn override_of<'a, T: ?Sized>(
+    server: &'a ServerEntry,
+    field: impl FnOnce(&'a ServerMessages) -> Option<&'a T>,
+) -> Option<&'a T> {
+    server.messages.as_ref().and_then(field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_file(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("madproxy-{}-{}.yml", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn pairs(items: &[(&str, &str)]) -> Vec<(String, String)> {
+        items.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn slot(yaml: &str) -> MotdSlot {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    /// Seconds since the epoch at `HH:MM` UTC on some day.
+    fn at(hours: u64, minutes: u64) -> u64 {
+        20_000 * 86_400 + hours * 3600 + minutes * 60
+    }
+
+    #[test]
+    fn parses_times_of_day() {
+        assert_eq!(parse_time_of_day("00:00"), Some(0));
+        assert_eq!(parse_time_of_day("8:05"), Some(485));
+        assert_eq!(parse_time_of_day(" 23:59 "), Some(1439));
+        for invalid in ["24:00", "12:60", "12", "12:", "noon", "-1:00", "12:30:00", ""] {
+            assert_eq!(parse_time_of_day(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn rotates_on_a_timer() {
+        let rotate = slot(
+            "
+mode: rotate
+interval_secs: 60
+entries:
+  - { text: a, protocol_name: A }
+  - { text: b, protocol_name: B }
+  - { text: c, protocol_name: C }
+",
+        );
+        let texts: Vec<&str> = [0, 59, 60, 130, 180, 240].iter().map(|&now| rotate.pick_at(now).text.as_str()).collect();
+        assert_eq!(texts, ["a", "a", "b", "c", "a", "b"]);
+        assert!(rotate.problems("motd").is_empty());
+
+        let single = slot("{ text: only, protocol_name: Only }");
+        assert_eq!(single.pick_at(12345).text, "only");
+    }
+
+    #[test]
+    fn follows_the_schedule() {
+        let schedule = slot(
+            "
+mode: schedule
+entries:
+  - { text: night, protocol_name: N, from: '22:00', until: '06:00' }
+  - { text: day, protocol_name: D, from: '09:00', until: '17:00' }
+  - { text: other, protocol_name: O }
+",
+        );
+        let cases = [
+            ((21, 59), "other"),
+            ((22, 0), "night"),
+            ((0, 0), "night"),
+            ((5, 59), "night"),
+            ((6, 0), "other"),
+            ((9, 0), "day"),
+            ((16, 59), "day"),
+            ((17, 0), "other"),
+        ];
+        for ((hours, minutes), expected) in cases {
+            assert_eq!(schedule.pick_at(at(hours, minutes)).text, expected, "{:02}:{:02}", hours, minutes);
+        }
+
+        let gaps = slot("{ mode: schedule, entries: [{ text: day, protocol_name: D, from: '09:00', until: '17:00' }] }");
+        assert_eq!(gaps.pick_at(at(20, 0)).text, "");
+        let invalid = slot("{ mode: schedule, entries: [{ text: day, protocol_name: D, from: '9am' }] }");
+        assert_eq!(invalid.problems("motd"), ["motd.entries[0].from: \"9am\" is not a HH:MM time"]);
+    }
+
+    #[test]
+    fn env_overrides_map_names_to_paths() {
+        let vars = pairs(&[
+            ("MADPROXY_HOLD_LOGIN__ENABLED", "true"),
+            ("MADPROXY_API_KEY", "123456"),
+            ("MADPROXY_CONFIG", "other.yml"),
+            ("HOME", "/root"),
+        ]);
+        let overrides = Config::env_overrides(vars.into_iter());
+        assert_eq!(overrides, pairs(&[("api_key", "123456"), ("hold_login.enabled", "true")]));
+    }
+
+    #[test]
+    fn numeric_looking_secrets_stay_strings() {
+        let path = config_file("secrets", "listen_addr: 0.0.0.0:25565\n");
+        for raw in ["123456", "1e5", "0x10", "true", "007"] {
+            let config = Config::load_layered(&path, &pairs(&[("api_key", raw)])).unwrap();
+            assert_eq!(config.api_key.expose(), raw);
+        }
+        let config = Config::load_layered(&path, &pairs(&[("panel_link", "8080")])).unwrap();
+        assert_eq!(config.panel_link, "8080");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn typed_fields_are_parsed() {
+        let path = config_file("typed", "hold_login:\n  enabled: false\n");
+        let overrides = pairs(&[("hold_login.enabled", "true"), ("hold_login.timeout_secs", "20")]);
+        let config = Config::load_layered(&path, &overrides).unwrap();
+        assert!(config.hold_login.enabled);
+        assert_eq!(config.hold_login.timeout_secs, 20);
+
+        let err = Config::load_layered(&path, &pairs(&[("hold_login.timeout_secs", "soon")])).unwrap_err();
+        assert!(err.to_string().starts_with("environment or command line override"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let path = config_file("layers", "listen_addr: 0.0.0.0:1\nhold_login:\n  timeout_secs: 5\n");
+        let mut overrides = Config::env_overrides(
+            pairs(&[("MADPROXY_LISTEN_ADDR", "0.0.0.0:2"), ("MADPROXY_HOLD_LOGIN__TIMEOUT_SECS", "10")]).into_iter(),
+        );
+        overrides.extend(pairs(&[("listen_addr", "0.0.0.0:3")]));
+        let config = Config::load_layered(&path, &overrides).unwrap();
+        assert_eq!(config.listen_addr, "0.0.0.0:3");
+        assert_eq!(config.hold_login.timeout_secs, 10);
+
+        let config = Config::load_layered(&path, &[]).unwrap();
+        assert_eq!(config.listen_addr, "0.0.0.0:1");
+        assert_eq!(config.hold_login.timeout_secs, 5);
+        std::fs::remove_file(path).unwrap();
     }
 }
@@ -0,0 +1,620 @@
+use crate::core::config::Config;
+use crate::core::power::PowerState;
+use crate::core::secret::Secret;
+use crate::core::servers::ServerEntry;
+use crate::utils::unix_http;
+use anyhow::Result;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, Method};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PowerSignal {
+    Start,
+    Stop,
+    Restart,
+    Kill,
+}
+
+impl PowerSignal {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Start => "start",
+            Self::Stop => "stop",
+            Self::Restart => "restart",
+            Self::Kill => "kill",
+        }
+    }
+}
+
+/// Something that can power a backend on and off.
+pub trait PowerController: Send + Sync + std::fmt::Debug {
+    fn send(&self, signal: PowerSignal) -> BoxFuture<'_, Result<()>>;
+
+    /// Asks the backend what it is doing. `None` means the controller has no
+    /// way of knowing.
+    fn query(&self) -> BoxFuture<'_, Result<Option<PowerState>>> {
+        Box::pin(async { Ok(None) })
+    }
+}
+
+/// How a server is powered, as written in `servers.json`. Servers without a
+/// `power` section use the Pterodactyl panel from `config.yml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PowerConfig {
+    Pterodactyl(PanelCredentials),
+    Webhook {
+        url: String,
+        #[serde(default = "default_webhook_method")]
+        method: String,
+        /// Header values and the body often carry tokens, so they are kept
+        /// as secrets and never show up in logs
+        #[serde(default)]
+        headers: HashMap<String, Secret>,
+        /// Request body, `{signal}` and `{server_id}` are substituted
+        #[serde(default)]
+        body: Option<Secret>,
+    },
+    Command {
+        /// Run through `sh -c` with `MADPROXY_SIGNAL` and `MADPROXY_SERVER_ID` set
+        command: String,
+    },
+    Docker {
+        #[serde(default = "default_docker_socket")]
+        socket: PathBuf,
+        /// Container name or id, defaults to the server id
+        #[serde(default)]
+        container: Option<String>,
+    },
+}
+
+/// Per-server Pterodactyl settings. Anything left unset falls back to
+/// `panel_link` and `api_key` in `config.yml`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PanelCredentials {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub panel_link: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<Secret>,
+    /// Name of an environment variable holding the API key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_env: Option<String>,
+    /// File whose contents are the API key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_file: Option<PathBuf>,
+    /// Identifier of the server on the panel, defaults to the entry id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_id: Option<String>,
+}
+
+impl PanelCredentials {
+    fn resolve(&self, config: &Config, server: &ServerEntry) -> Result<Pterodactyl> {
+        let api_key = match Secret::resolve(self.api_key.as_ref(), self.api_key_env.as_deref(), self.api_key_file.as_deref())? {
+            Some(key) => key,
+            None => config.api_key()?,
+        };
+        let panel_link = self.panel_link.clone().unwrap_or_else(|| config.panel_link());
+
+        if api_key.is_empty() {
+            return Err(anyhow!("API key is empty"));
+        }
+        if panel_link.trim().is_empty() {
+            return Err(anyhow!("panel link is empty"));
+        }
+        Ok(Pterodactyl {
+            panel_link: panel_link.trim_end_matches('/').to_string(),
+            api_key,
+            server_id: self.server_id.clone().unwrap_or_else(|| server.id.clone()),
+        })
+    }
+}
+
+impl PowerConfig {
+    /// Replaces every credential with a placeholder, for printing.
+    pub fn redact(&mut self) {
+        match self {
+            Self::Pterodactyl(credentials) => {
+                credentials.api_key = credentials.api_key.as_ref().map(Secret::redacted);
+            }
+            Self::Webhook { headers, body, .. } => {
+                headers.values_mut().for_each(|value| *value = value.redacted());
+                *body = body.as_ref().map(Secret::redacted);
+            }
+            Self::Command { .. } | Self::Docker { .. } => {}
+        }
+    }
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self::Pterodactyl(PanelCredentials::default())
+    }
+}
+
+fn default_webhook_method() -> String {
+    "POST".to_string()
+}
+
+pub fn default_docker_socket() -> PathBuf {
+    PathBuf::from("/var/run/docker.sock")
+}
+
+pub fn controller_for(config: &Config, server: &ServerEntry) -> Result<Box<dyn PowerController>> {
+    Ok(match server.power.clone().unwrap_or_default() {
+        PowerConfig::Pterodactyl(credentials) => Box::new(credentials.resolve(config, server)?),
+        PowerConfig::Webhook { url, method, headers, body } => Box::new(Webhook {
+            url,
+            method,
+            headers,
+            body,
+            server_id: server.id.clone(),
+        }),
+        PowerConfig::Command { command } => Box::new(ShellCommand {
+            command,
+            server_id: server.id.clone(),
+        }),
+        PowerConfig::Docker { socket, container } => Box::new(Docker {
+            socket,
+            container: container.unwrap_or_else(|| server.id.clone()),
+        }),
+    })
+}
+
+/// Checks that every server can be powered, so missing credentials show up at
+/// startup rather than on the first join. Returns one message per problem.
+pub fn validate_controllers<'a>(config: &Config, servers: impl IntoIterator<Item = &'a ServerEntry>) -> Vec<String> {
+    servers
+        .into_iter()
+        .filter_map(|server| {
+            controller_for(config, server)
+                .err()
+                .map(|e| format!("server {}: {}", server.id, e))
+        })
+        .collect()
+}
+
+/// Pterodactyl client API, `POST /api/client/servers/{id}/power`.
+#[derive(Debug)]
+pub struct Pterodactyl {
+    panel_link: String,
+    api_key: Secret,
+    server_id: String,
+}
+
+impl Pterodactyl {
+    fn headers(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", HeaderValue::from_str(&format!("Bearer {}", self.api_key.expose()))?);
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert("Accept", HeaderValue::from_static("application/json"));
+        Ok(headers)
+    }
+
+    async fn get(&self, path: &str) -> Result<Value> {
+        let url = format!("{}/api/client/servers/{}{}", self.panel_link, self.server_id, path);
+        let response = Client::new().get(url).headers(self.headers()?).send().await?;
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        if !status.is_success() {
+            return Err(self.panel_error(status.as_u16(), &body));
+        }
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// Turns a failed panel response into an error that says what went wrong.
+    fn panel_error(&self, status: u16, body: &str) -> anyhow::Error {
+        let detail = serde_json::from_str::<Value>(body)
+            .ok()
+            .and_then(|v| v["errors"][0]["detail"].as_str().map(str::to_string))
+            .unwrap_or_else(|| body.trim().to_string());
+        match status {
+            401 | 403 => anyhow!("panel rejected the API key ({}): {}", status, detail),
+            404 => anyhow!("server {} does not exist on the panel", self.server_id),
+            _ => anyhow!("panel returned {}: {}", status, detail),
+        }
+    }
+}
+
+/// Maps Pterodactyl's `status` field and `/resources` state onto ours.
+fn pterodactyl_state(status: Option<&str>, current_state: Option<&str>) -> PowerState {
+    match status {
+        Some("suspended") => return PowerState::Suspended,
+        Some("installing") | Some("install_failed") | Some("reinstall_failed") | Some("restoring_backup") => {
+            return PowerState::Installing
+        }
+        _ => {}
+    }
+    match current_state {
+        Some("starting") => PowerState::Starting,
+        Some("running") => PowerState::Running,
+        Some("stopping") => PowerState::Stopping,
+        _ => PowerState::Idle,
+    }
+}
+
+impl PowerController for Pterodactyl {
+    fn send(&self, signal: PowerSignal) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let url = format!("{}/api/client/servers/{}/power", self.panel_link, self.server_id);
+            let response = Client::new()
+                .post(url)
+                .headers(self.headers()?)
+                .json(&json!({
+                    "signal": signal.as_str()
+                }))
+                .send()
+                .await?;
+            let status = response.status();
+            if status.is_success() {
+                return Ok(());
+            }
+            let body = response.text().await.unwrap_or_default();
+            Err(self.panel_error(status.as_u16(), &body))
+        })
+    }
+
+    fn query(&self) -> BoxFuture<'_, Result<Option<PowerState>>> {
+        Box::pin(async move {
+            let details = self.get("").await?;
+            let attributes = &details["attributes"];
+            if attributes["is_suspended"].as_bool() == Some(true) {
+                return Ok(Some(PowerState::Suspended));
+            }
+            if attributes["is_installing"].as_bool() == Some(true) {
+                return Ok(Some(PowerState::Installing));
+            }
+            let status = attributes["status"].as_str();
+            if status.is_some() {
+                return Ok(Some(pterodactyl_state(status, None)));
+            }
+            let resources = self.get("/resources").await?;
+            Ok(Some(pterodactyl_state(None, resources["attributes"]["current_state"].as_str())))
+        })
+    }
+}
+
+/// Any HTTP endpoint, for panels and orchestrators without a dedicated
+/// integration.
+#[derive(Debug)]
+pub struct Webhook {
+    url: String,
+    method: String,
+    headers: HashMap<String, Secret>,
+    body: Option<Secret>,
+    server_id: String,
+}
+
+impl Webhook {
+    fn render(&self, template: &str, signal: PowerSignal) -> String {
+        template
+            .replace("{signal}", signal.as_str())
+            .replace("{server_id}", &self.server_id)
+    }
+}
+
+impl PowerController for Webhook {
+    fn send(&self, signal: PowerSignal) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let method = Method::from_bytes(self.method.to_uppercase().as_bytes())?;
+            let mut headers = HeaderMap::new();
+            for (name, value) in &self.headers {
+                headers.insert(HeaderName::from_bytes(name.as_bytes())?, HeaderValue::from_str(&self.render(value.expose(), signal))?);
+            }
+            let mut request = Client::new()
+                .request(method, self.render(&self.url, signal))
+                .headers(headers);
+            if let Some(body) = &self.body {
+                request = request.body(self.render(body.expose(), signal));
+            }
+            check_status(request.send().await?).await
+        })
+    }
+}
+
+/// A local command, for backends managed by systemd, scripts and the like.
+#[derive(Debug)]
+pub struct ShellCommand {
+    command: String,
+    server_id: String,
+}
+
+impl PowerController for ShellCommand {
+    fn send(&self, signal: PowerSignal) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let output = tokio::process::Command::new("sh")
+                .arg("-c")
+                .arg(&self.command)
+                .env("MADPROXY_SIGNAL", signal.as_str())
+                .env("MADPROXY_SERVER_ID", &self.server_id)
+                .output()
+                .await?;
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "Power command exited with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Docker Engine API over its unix socket.
+#[derive(Debug)]
+pub struct Docker {
+    socket: PathBuf,
+    container: String,
+}
+
+impl PowerController for Docker {
+    fn send(&self, signal: PowerSignal) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let path = format!("/containers/{}/{}", self.container, signal.as_str());
+            let (status, body) = unix_http::request(&self.socket, "POST", &path, None).await?;
+            match status {
+                // 304 means the container already is in the requested state
+                200..=299 | 304 => Ok(()),
+                _ => Err(anyhow!("Docker returned {}: {}", status, body.trim())),
+            }
+        })
+    }
+
+    fn query(&self) -> BoxFuture<'_, Result<Option<PowerState>>> {
+        Box::pin(async move {
+            let path = format!("/containers/{}/json", self.container);
+            let (status, body) = unix_http::request(&self.socket, "GET", &path, None).await?;
+            if status != 200 {
+                return Err(anyhow!("Docker returned {}: {}", status, body.trim()));
+            }
+            let inspect: Value = serde_json::from_str(&body)?;
+            Ok(Some(match inspect["State"]["Status"].as_str() {
+                Some("running") => PowerState::Running,
+                Some("restarting") => PowerState::Starting,
+                Some("removing") => PowerState::Stopping,
+                _ => PowerState::Idle,
+            }))
+        })
+    }
+}
+
+async fn check_status(response: reqwest::Response) -> Result<()> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(anyhow!("Power request returned {}: {}", status, body.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::unix_http::fake;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    /// Answers every request on a local port with what `respond` returns for
+    /// its request line, and passes each request on. Returns the base URL.
+    async fn serve_http(
+        respond: impl Fn(&str) -> (u16, String) + Send + 'static,
+    ) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (requests, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let request = fake::read_request(&mut stream).await;
+                let (status, body) = respond(request.lines().next().unwrap_or_default());
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                requests.send(request).unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    fn controller(power: Value) -> Box<dyn PowerController> {
+        controller_for(&Config::default(), &ServerEntry::for_test("lobby", power)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn pterodactyl_posts_the_signal() {
+        let (url, mut requests) = serve_http(|_| (204, String::new())).await;
+        let panel = controller(json!({"type": "pterodactyl", "panel_link": format!("{}/", url), "api_key": "ptlc_key", "server_id": "1a2b"}));
+
+        panel.send(PowerSignal::Restart).await.unwrap();
+        let request = requests.recv().await.unwrap();
+        assert!(request.starts_with("POST /api/client/servers/1a2b/power HTTP/1.1\r\n"), "{}", request);
+        assert!(request.to_lowercase().contains("authorization: bearer ptlc_key\r\n"), "{}", request);
+        assert!(request.ends_with(r#"{"signal":"restart"}"#), "{}", request);
+    }
+
+    #[tokio::test]
+    async fn pterodactyl_defaults_to_the_entry_id_and_global_panel() {
+        let (url, mut requests) = serve_http(|_| (204, String::new())).await;
+        let config = Config {
+            panel_link: url,
+            api_key: Secret::new("global_key"),
+            ..Config::default()
+        };
+        let panel = controller_for(&config, &ServerEntry::for_test("lobby", json!({"type": "pterodactyl"}))).unwrap();
+
+        panel.send(PowerSignal::Start).await.unwrap();
+        let request = requests.recv().await.unwrap();
+        assert!(request.starts_with("POST /api/client/servers/lobby/power "), "{}", request);
+        assert!(request.to_lowercase().contains("authorization: bearer global_key\r\n"), "{}", request);
+    }
+
+    #[tokio::test]
+    async fn pterodactyl_reports_panel_errors() {
+        let (url, _requests) = serve_http(|line| match line {
+            l if l.contains("/forbidden/") => (403, r#"{"errors":[{"detail":"This action is unauthorized."}]}"#.to_string()),
+            l if l.contains("/missing/") => (404, String::new()),
+            _ => (502, "Bad Gateway".to_string()),
+        })
+        .await;
+        let panel = |server_id: &str| {
+            controller(json!({"type": "pterodactyl", "panel_link": url, "api_key": "k", "server_id": server_id}))
+        };
+
+        let error = panel("forbidden").send(PowerSignal::Start).await.unwrap_err().to_string();
+        assert!(error.contains("API key") && error.contains("This action is unauthorized."), "{}", error);
+        let error = panel("missing").send(PowerSignal::Start).await.unwrap_err().to_string();
+        assert!(error.contains("does not exist"), "{}", error);
+        let error = panel("other").query().await.unwrap_err().to_string();
+        assert!(error.contains("502") && error.contains("Bad Gateway"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn pterodactyl_queries_the_power_state() {
+        let (url, _requests) = serve_http(|line| {
+            let body = match line.split_whitespace().nth(1).unwrap_or_default() {
+                "/api/client/servers/running" => json!({"attributes": {"status": null}}),
+                "/api/client/servers/running/resources" => json!({"attributes": {"current_state": "running"}}),
+                "/api/client/servers/suspended" => json!({"attributes": {"is_suspended": true}}),
+                "/api/client/servers/installing" => json!({"attributes": {"status": "installing"}}),
+                _ => return (404, String::new()),
+            };
+            (200, body.to_string())
+        })
+        .await;
+        for (server_id, state) in [
+            ("running", PowerState::Running),
+            ("suspended", PowerState::Suspended),
+            ("installing", PowerState::Installing),
+        ] {
+            let panel = controller(json!({"type": "pterodactyl", "panel_link": url, "api_key": "k", "server_id": server_id}));
+            assert_eq!(panel.query().await.unwrap(), Some(state), "{}", server_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn webhook_fills_in_the_templates() {
+        let (url, mut requests) = serve_http(|_| (200, String::new())).await;
+        let webhook = controller(json!({
+            "type": "webhook",
+            "url": format!("{}/servers/{{server_id}}/{{signal}}", url),
+            "method": "put",
+            "headers": {"X-Signal": "{signal}", "Authorization": "Bearer token"},
+            "body": r#"{"server":"{server_id}","action":"{signal}"}"#,
+        }));
+
+        webhook.send(PowerSignal::Stop).await.unwrap();
+        let request = requests.recv().await.unwrap();
+        let lowercase = request.to_lowercase();
+        assert!(request.starts_with("PUT /servers/lobby/stop HTTP/1.1\r\n"), "{}", request);
+        assert!(lowercase.contains("x-signal: stop\r\n"), "{}", request);
+        assert!(lowercase.contains("authorization: bearer token\r\n"), "{}", request);
+        assert!(request.ends_with(r#"{"server":"lobby","action":"stop"}"#), "{}", request);
+    }
+
+    #[tokio::test]
+    async fn webhook_defaults_to_an_empty_post() {
+        let (url, mut requests) = serve_http(|_| (204, String::new())).await;
+        controller(json!({"type": "webhook", "url": url})).send(PowerSignal::Kill).await.unwrap();
+        let request = requests.recv().await.unwrap();
+        assert!(request.starts_with("POST / HTTP/1.1\r\n"), "{}", request);
+        assert!(request.ends_with("\r\n\r\n"), "{}", request);
+    }
+
+    #[tokio::test]
+    async fn webhook_fails_on_error_status() {
+        let (url, _requests) = serve_http(|line| {
+            if line.contains("/moved") {
+                (302, String::new())
+            } else {
+                (500, "backend on fire".to_string())
+            }
+        })
+        .await;
+        let error = controller(json!({"type": "webhook", "url": url}))
+            .send(PowerSignal::Start)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("500") && error.contains("backend on fire"), "{}", error);
+        let moved = controller(json!({"type": "webhook", "url": format!("{}/moved", url)}));
+        assert!(moved.send(PowerSignal::Start).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn command_sees_the_signal_and_server() {
+        let command = controller(json!({
+            "type": "command",
+            "command": r#"test "$MADPROXY_SIGNAL" = start && test "$MADPROXY_SERVER_ID" = lobby"#,
+        }));
+        command.send(PowerSignal::Start).await.unwrap();
+        assert!(command.send(PowerSignal::Stop).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn command_fails_on_nonzero_exit() {
+        let command = controller(json!({"type": "command", "command": "echo 'no such unit' >&2; exit 3"}));
+        let error = command.send(PowerSignal::Start).await.unwrap_err().to_string();
+        assert!(error.contains("exit status: 3") && error.contains("no such unit"), "{}", error);
+    }
+
+    fn serve_docker(name: &str) -> (PathBuf, mpsc::UnboundedReceiver<String>) {
+        let (requests, received) = mpsc::unbounded_channel();
+        let socket = fake::serve(name, move |request, mut stream| {
+            let path = request.split_whitespace().nth(1).unwrap_or_default().to_string();
+            requests.send(request).unwrap();
+            async move {
+                let (status, body) = match path.as_str() {
+                    "/containers/started/start" => (304, String::new()),
+                    "/containers/started/json" => (200, json!({"State": {"Status": "running"}}).to_string()),
+                    "/containers/exited/json" => (200, json!({"State": {"Status": "exited"}}).to_string()),
+                    p if p.starts_with("/containers/missing/") => (404, r#"{"message":"No such container"}"#.to_string()),
+                    _ => (204, String::new()),
+                };
+                let response = format!("HTTP/1.1 {} Status\r\nContent-Length: {}\r\n\r\n{}", status, body.len(), body);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (socket, received)
+    }
+
+    #[tokio::test]
+    async fn docker_posts_the_signal() {
+        let (socket, mut requests) = serve_docker("controller-signal");
+        let docker = controller(json!({"type": "docker", "socket": socket}));
+        docker.send(PowerSignal::Restart).await.unwrap();
+        let request = requests.recv().await.unwrap();
+        assert!(request.starts_with("POST /containers/lobby/restart HTTP/1.1\r\n"), "{}", request);
+    }
+
+    #[tokio::test]
+    async fn docker_treats_not_modified_as_success() {
+        let (socket, _requests) = serve_docker("controller-304");
+        let docker = controller(json!({"type": "docker", "socket": socket, "container": "started"}));
+        docker.send(PowerSignal::Start).await.unwrap();
+
+        let missing = controller(json!({"type": "docker", "socket": socket, "container": "missing"}));
+        let error = missing.send(PowerSignal::Start).await.unwrap_err().to_string();
+        assert!(error.contains("404") && error.contains("No such container"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn docker_queries_the_container_state() {
+        let (socket, _requests) = serve_docker("controller-query");
+        let query = |container: &str| controller(json!({"type": "docker", "socket": socket, "container": container}));
+        assert_eq!(query("started").query().await.unwrap(), Some(PowerState::Running));
+        assert_eq!(query("exited").query().await.unwrap(), Some(PowerState::Idle));
+        assert!(query("missing").query().await.is_err());
+    }
+}
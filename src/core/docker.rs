@@ -0,0 +1,327 @@
+use crate::core::config::DockerDiscovery;
+use crate::core::controller::PowerConfig;
+use crate::core::servers::{ServerEntry, Servers};
+use crate::utils::unix_http;
+use anyhow::Result;
+use serde_json::Value;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+const LABEL_HOSTNAMES: &str = "madproxy.hostnames";
+const LABEL_PORT: &str = "madproxy.port";
+const LABEL_PROXY_PROTOCOL: &str = "madproxy.proxy_protocol";
+const LABEL_ID: &str = "madproxy.id";
+
+const DEFAULT_PORT: u16 = 25565;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Keeps the discovered servers in sync with the containers carrying a
+/// `madproxy.hostnames` label. Runs forever.
+pub async fn run(settings: DockerDiscovery, servers: Servers) {
+    let changed = Arc::new(Notify::new());
+
+    let syncer = {
+        let settings = settings.clone();
+        let changed = changed.clone();
+        async move {
+            loop {
+                match list_servers(&settings).await {
+                    Ok(entries) => servers.set_discovered(entries),
+                    Err(e) => warn!("Docker discovery failed, keeping previous servers: {}", e),
+                }
+                changed.notified().await;
+            }
+        }
+    };
+    tokio::spawn(syncer);
+
+    let filters = format!(
+        r#"{{"type":["container"],"event":["create","start","die","destroy","rename","update"],"label":["{}"]}}"#,
+        LABEL_HOSTNAMES
+    );
+    let path = format!("/events?filters={}", encode_query(&filters));
+    loop {
+        let result = unix_http::stream_lines(&settings.socket, &path, |_| changed.notify_one()).await;
+        match result {
+            Ok(()) => warn!("Docker event stream ended, reconnecting"),
+            Err(e) => warn!("Docker event stream failed, reconnecting: {}", e),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+        // Events may have been missed while disconnected
+        changed.notify_one();
+    }
+}
+
+async fn list_servers(settings: &DockerDiscovery) -> Result<Vec<ServerEntry>> {
+    let filters = format!(r#"{{"label":["{}"]}}"#, LABEL_HOSTNAMES);
+    let path = format!("/containers/json?all=true&filters={}", encode_query(&filters));
+    let (status, body) = unix_http::request(&settings.socket, "GET", &path, None).await?;
+    if status != 200 {
+        return Err(anyhow!("Docker returned {}: {}", status, body.trim()));
+    }
+    let containers: Vec<Value> = serde_json::from_str(&body)?;
+    Ok(containers
+        .iter()
+        .filter_map(|container| entry_from_container(settings, container))
+        .collect())
+}
+
+/// Builds a server from a container in the `/containers/json` listing. A
+/// running container is reached by its IP address, a stopped one by name, so
+/// it can still be started through Docker on join.
+fn entry_from_container(settings: &DockerDiscovery, container: &Value) -> Option<ServerEntry> {
+    let labels = &container["Labels"];
+    let label = |key: &str| labels[key].as_str().map(str::trim).filter(|v| !v.is_empty());
+
+    let container_id = container["Id"].as_str()?;
+    let name = container["Names"][0]
+        .as_str()
+        .map(|n| n.trim_start_matches('/'))
+        .unwrap_or(container_id);
+    let hostnames: Vec<String> = label(LABEL_HOSTNAMES)?
+        .split(',')
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .collect();
+    if hostnames.is_empty() {
+        return None;
+    }
+    let port = match label(LABEL_PORT).map(str::parse::<u16>) {
+        Some(Ok(port)) => port,
+        Some(Err(_)) => {
+            warn!("Container {} has an invalid {} label, skipping", name, LABEL_PORT);
+            return None;
+        }
+        None => DEFAULT_PORT,
+    };
+    let proxy_protocol = label(LABEL_PROXY_PROTOCOL)
+        .map(|v| matches!(v.to_ascii_lowercase().as_str(), "true" | "1" | "yes"))
+        .unwrap_or(true);
+
+    let running = container["State"].as_str() == Some("running");
+    let networks = container["NetworkSettings"]["Networks"].as_object();
+    let ip = networks.and_then(|networks| {
+        let address = |network: &Value| network["IPAddress"].as_str().filter(|ip| !ip.is_empty()).map(str::to_string);
+        match &settings.network {
+            Some(wanted) => networks.get(wanted).and_then(address),
+            None => networks.values().find_map(address),
+        }
+    });
+    let backend_server = match ip {
+        Some(ip) if running => format!("{}:{}", ip, port),
+        _ => format!("{}:{}", name, port),
+    };
+
+    Some(ServerEntry {
+        id: label(LABEL_ID).unwrap_or(name).to_string(),
+        hostnames,
+        backend_server,
+        proxy_protocol,
+        power: Some(PowerConfig::Docker {
+            socket: settings.socket.clone(),
+            container: Some(container_id.to_string()),
+        }),
+        auto_stop: None,
+        idle_timeout_secs: None,
+        min_uptime_secs: None,
+        version_routes: Vec::new(),
+        versions: None,
+        maintenance: false,
+        messages: None,
+        player_count: AtomicUsize::new(0),
+    })
+}
+
+/// Percent-encodes everything but unreserved characters.
+fn encode_query(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config::Config;
+    use crate::core::controller::default_docker_socket;
+    use crate::utils::unix_http::fake;
+    use serde_json::json;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use tokio::io::AsyncWriteExt;
+
+    fn settings(socket: PathBuf) -> DockerDiscovery {
+        DockerDiscovery {
+            enabled: true,
+            socket,
+            network: None,
+        }
+    }
+
+    fn container(name: &str, state: &str, labels: Value) -> Value {
+        json!({
+            "Id": format!("{}-id", name),
+            "Names": [format!("/{}", name)],
+            "State": state,
+            "Labels": labels,
+            "NetworkSettings": {"Networks": {
+                "bridge": {"IPAddress": if state == "running" { "172.17.0.2" } else { "" }},
+                "proxy": {"IPAddress": if state == "running" { "10.0.0.2" } else { "" }},
+            }},
+        })
+    }
+
+    /// Answers `/containers/json` with whatever `containers` holds at the time.
+    fn serve_containers(name: &str, containers: Arc<Mutex<Value>>) -> PathBuf {
+        fake::serve(name, move |request, mut stream| {
+            let body = containers.lock().unwrap().to_string();
+            async move {
+                assert!(request.starts_with("GET /containers/json?all=true&filters="), "{}", request);
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        })
+    }
+
+    fn assert_docker_power(entry: &ServerEntry, socket: &PathBuf, id: &str) {
+        match &entry.power {
+            Some(PowerConfig::Docker { socket: s, container }) => {
+                assert_eq!(s, socket);
+                assert_eq!(container.as_deref(), Some(id));
+            }
+            other => panic!("unexpected power config {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_labelled_containers() {
+        let containers = json!([
+            container("lobby", "running", json!({
+                "madproxy.hostnames": "lobby.example, www.lobby.example,",
+                "madproxy.port": "25570",
+                "madproxy.proxy_protocol": "false",
+                "madproxy.id": "hub",
+            })),
+            container("survival", "exited", json!({"madproxy.hostnames": "survival.example"})),
+            container("broken", "running", json!({"madproxy.hostnames": "broken.example", "madproxy.port": "high"})),
+            container("blank", "running", json!({"madproxy.hostnames": " , "})),
+        ]);
+        let socket = serve_containers("docker-list", Arc::new(Mutex::new(containers)));
+
+        let entries = list_servers(&settings(socket.clone())).await.unwrap();
+        assert_eq!(entries.len(), 2);
+
+        let lobby = &entries[0];
+        assert_eq!(lobby.id, "hub");
+        assert_eq!(lobby.hostnames, ["lobby.example", "www.lobby.example"]);
+        assert_eq!(lobby.backend_server, "172.17.0.2:25570");
+        assert!(!lobby.proxy_protocol);
+        assert_docker_power(lobby, &socket, "lobby-id");
+
+        // Stopped containers have no address and are reached by name
+        let survival = &entries[1];
+        assert_eq!(survival.id, "survival");
+        assert_eq!(survival.hostnames, ["survival.example"]);
+        assert_eq!(survival.backend_server, "survival:25565");
+        assert!(survival.proxy_protocol);
+        assert_docker_power(survival, &socket, "survival-id");
+    }
+
+    #[test]
+    fn uses_the_configured_network() {
+        let running = container("lobby", "running", json!({"madproxy.hostnames": "lobby.example"}));
+        let mut settings = settings(default_docker_socket());
+        settings.network = Some("proxy".to_string());
+        assert_eq!(entry_from_container(&settings, &running).unwrap().backend_server, "10.0.0.2:25565");
+        settings.network = Some("missing".to_string());
+        assert_eq!(entry_from_container(&settings, &running).unwrap().backend_server, "lobby:25565");
+    }
+
+    #[test]
+    fn parses_proxy_protocol_labels() {
+        let settings = settings(default_docker_socket());
+        for (value, expected) in [("true", true), ("YES", true), ("1", true), ("false", false), ("0", false), ("", true)] {
+            let labels = json!({"madproxy.hostnames": "a.example", "madproxy.proxy_protocol": value});
+            let entry = entry_from_container(&settings, &container("a", "running", labels)).unwrap();
+            assert_eq!(entry.proxy_protocol, expected, "{:?}", value);
+        }
+    }
+
+    #[tokio::test]
+    async fn list_fails_on_error_status() {
+        let socket = fake::serve("docker-error", |_, mut stream| async move {
+            let response = "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 4\r\n\r\nboom";
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        let error = list_servers(&settings(socket)).await.unwrap_err();
+        assert!(error.to_string().contains("500"), "{}", error);
+    }
+
+    async fn eventually(what: &str, check: impl Fn() -> bool) {
+        let waited = tokio::time::timeout(Duration::from_secs(5), async {
+            while !check() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        waited.await.unwrap_or_else(|_| panic!("timed out waiting for {}", what));
+    }
+
+    #[tokio::test]
+    async fn resyncs_when_an_event_arrives() {
+        let containers = Arc::new(Mutex::new(json!([
+            container("lobby", "running", json!({"madproxy.hostnames": "lobby.example"})),
+        ])));
+        let event = Arc::new(Notify::new());
+        let socket = {
+            let containers = containers.clone();
+            let event = event.clone();
+            fake::serve("docker-events", move |request, mut stream| {
+                let body = containers.lock().unwrap().to_string();
+                let event = event.clone();
+                async move {
+                    if request.starts_with("GET /events?filters=") {
+                        stream.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n").await.unwrap();
+                        event.notified().await;
+                        let line = "{\"Type\":\"container\",\"Action\":\"start\"}\n";
+                        stream.write_all(format!("{:x}\r\n{}\r\n", line.len(), line).as_bytes()).await.unwrap();
+                        // Keep the stream open, as Docker does
+                        std::future::pending::<()>().await;
+                    } else {
+                        let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                    }
+                }
+            })
+        };
+        let config = Config {
+            servers: Some(Vec::new()),
+            ..Config::default()
+        };
+        let servers = Servers::load(&config).unwrap();
+        let discovery = tokio::spawn(run(settings(socket), servers.clone()));
+
+        eventually("the first listing", || servers.get_by_hostname("lobby.example").is_some()).await;
+        assert!(servers.get_by_hostname("survival.example").is_none());
+
+        containers.lock().unwrap().as_array_mut().unwrap().push(container(
+            "survival",
+            "running",
+            json!({"madproxy.hostnames": "survival.example"}),
+        ));
+        event.notify_one();
+        eventually("the listing after the event", || servers.get_by_hostname("survival.example").is_some()).await;
+        assert_eq!(servers.get_by_hostname("survival.example").unwrap().backend_server, "172.17.0.2:25565");
+        discovery.abort();
+    }
+
+    #[test]
+    fn encodes_filters() {
+        assert_eq!(encode_query(r#"{"label":["a.b"]}"#), "%7B%22label%22%3A%5B%22a.b%22%5D%7D");
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+
+/// File formats accepted for the configuration and the server registry.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Result<Format> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase())
+            .unwrap_or_default();
+        match extension.as_str() {
+            "yml" | "yaml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            "json" => Ok(Format::Json),
+            _ => Err(anyhow!(
+                "{}: unknown format, expected a .yml, .yaml, .toml or .json extension",
+                path.display()
+            )),
+        }
+    }
+
+    /// Deserializes `text`. Errors name the offending field and, where the
+    /// format allows, its line and column.
+    pub fn parse<T: DeserializeOwned>(&self, text: &str) -> Result<T> {
+        Ok(match self {
+            Format::Yaml => serde_yaml::from_str(text)?,
+            Format::Toml => toml::from_str(text)?,
+            Format::Json => {
+                let deserializer = &mut serde_json::Deserializer::from_str(text);
+                serde_path_to_error::deserialize(deserializer)?
+            }
+        })
+    }
+
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<String> {
+        Ok(match self {
+            Format::Yaml => serde_yaml::to_string(value)?,
+            Format::Toml => toml::to_string_pretty(value)?,
+            Format::Json => serde_json::to_string_pretty(value)?,
+        })
+    }
+}
+
+/// Reads and deserializes a file in the format given by its extension.
+/// Errors are prefixed with the path.
+pub fn read_file<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let format = Format::from_path(path)?;
+    let text = std::fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    format
+        .parse(&text)
+        .map_err(|e| anyhow!("{}: {}", path.display(), e.to_string().trim_end()))
+}
+
+pub fn write_file<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let text = Format::from_path(path)?.serialize(value)?;
+    std::fs::write(path, text).map_err(|e| anyhow!("{}: {}", path.display(), e))
+}
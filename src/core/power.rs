@@ -0,0 +1,261 @@
+use derive_more::Display;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Display)]
+pub enum PowerState {
+    Idle,
+    Starting,
+    Running,
+    Stopping,
+    Suspended,
+    Installing,
+}
+
+#[derive(Debug, Clone)]
+struct ServerPower {
+    state: PowerState,
+    since: Instant,
+    last_start_request: Option<Instant>,
+    last_start_duration: Option<Duration>,
+    last_query: Option<Instant>,
+    errors: u64,
+}
+
+impl Default for ServerPower {
+    fn default() -> Self {
+        Self {
+            state: PowerState::Idle,
+            since: Instant::now(),
+            last_start_request: None,
+            last_start_duration: None,
+            last_query: None,
+            errors: 0,
+        }
+    }
+}
+
+impl ServerPower {
+    fn set(&mut self, state: PowerState) {
+        if self.state != state {
+            if self.state == PowerState::Starting && state == PowerState::Running {
+                self.last_start_duration = Some(self.since.elapsed());
+            }
+            self.state = state;
+            self.since = Instant::now();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StartDecision {
+    /// No start is pending, the caller should send the power signal.
+    Send,
+    /// Another connection already asked the panel to start this server.
+    InFlight,
+    /// A start was requested recently, wait for the remaining duration.
+    CoolingDown(Duration),
+}
+
+/// Tracks what the proxy believes each backend is doing, keyed by server id.
+/// Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct PowerTracker {
+    servers: Arc<Mutex<HashMap<String, ServerPower>>>,
+}
+
+impl PowerTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn state(&self, server_id: &str) -> PowerState {
+        self.servers
+            .lock()
+            .unwrap()
+            .get(server_id)
+            .map(|s| s.state)
+            .unwrap_or(PowerState::Idle)
+    }
+
+    /// How long the server has been in its current state.
+    pub fn elapsed(&self, server_id: &str) -> Option<Duration> {
+        self.servers
+            .lock()
+            .unwrap()
+            .get(server_id)
+            .map(|s| s.since.elapsed())
+    }
+
+    /// Time left until a starting server should be up, judged by how long its
+    /// previous start took, or by `start_timeout` if it never came up before.
+    pub fn start_eta(&self, server_id: &str, start_timeout: Duration) -> Option<Duration> {
+        let servers = self.servers.lock().unwrap();
+        let server = servers.get(server_id).filter(|s| s.state == PowerState::Starting)?;
+        let expected = server.last_start_duration.unwrap_or(start_timeout);
+        Some(expected.saturating_sub(server.since.elapsed()))
+    }
+
+    /// Decides whether a start signal should go out and, if so, marks the
+    /// server as starting so concurrent logins collapse onto this request.
+    pub fn begin_start(&self, server_id: &str, cooldown: Duration) -> StartDecision {
+        let mut servers = self.servers.lock().unwrap();
+        let server = servers.entry(server_id.to_string()).or_default();
+        if server.state == PowerState::Starting {
+            return StartDecision::InFlight;
+        }
+        if let Some(last) = server.last_start_request {
+            let since = last.elapsed();
+            if since < cooldown {
+                return StartDecision::CoolingDown(cooldown - since);
+            }
+        }
+        server.set(PowerState::Starting);
+        server.last_start_request = Some(Instant::now());
+        StartDecision::Send
+    }
+
+    /// Rolls back a start that the panel rejected. The cooldown still applies.
+    pub fn start_failed(&self, server_id: &str) {
+        if let Some(server) = self.servers.lock().unwrap().get_mut(server_id) {
+            server.set(PowerState::Idle);
+        }
+    }
+
+    pub fn mark_running(&self, server_id: &str) {
+        self.set_state(server_id, PowerState::Running);
+    }
+
+    pub fn mark_stopping(&self, server_id: &str) {
+        self.set_state(server_id, PowerState::Stopping);
+    }
+
+    /// Applies a state reported by the panel.
+    pub fn set_state(&self, server_id: &str, state: PowerState) {
+        self.servers
+            .lock()
+            .unwrap()
+            .entry(server_id.to_string())
+            .or_default()
+            .set(state);
+    }
+
+    /// Claims a state query for the server, so that a burst of joins asks the
+    /// panel once. Returns false while the last query is younger than
+    /// `max_age`, or while a start requested within `cooldown` is pending and
+    /// the panel has nothing new to say.
+    pub fn claim_query(&self, server_id: &str, max_age: Duration, cooldown: Duration) -> bool {
+        let mut servers = self.servers.lock().unwrap();
+        let server = servers.entry(server_id.to_string()).or_default();
+        let fresh = server.last_query.is_some_and(|at| at.elapsed() < max_age);
+        let start_pending = server.state == PowerState::Starting
+            && server.last_start_request.is_some_and(|at| at.elapsed() < cooldown);
+        if fresh || start_pending {
+            return false;
+        }
+        server.last_query = Some(Instant::now());
+        true
+    }
+
+    /// Logs a failed power request, `action` being what was attempted, along
+    /// with how many have failed for the server so far.
+    pub fn record_error(&self, server_id: &str, action: &str, error: &anyhow::Error) {
+        let errors = {
+            let mut servers = self.servers.lock().unwrap();
+            let server = servers.entry(server_id.to_string()).or_default();
+            server.errors += 1;
+            server.errors
+        };
+        error!("Failed to {} server {}: {} ({} failed requests so far)", action, server_id, error, errors);
+    }
+
+    /// Records a failed connection to the backend. A server that was running or
+    /// stopping is now idle, and a start that has been pending for longer than
+    /// `start_timeout` is given up on. Returns the resulting state.
+    pub fn mark_unreachable(&self, server_id: &str, start_timeout: Duration) -> PowerState {
+        let mut servers = self.servers.lock().unwrap();
+        let server = servers.entry(server_id.to_string()).or_default();
+        match server.state {
+            PowerState::Running | PowerState::Stopping => server.set(PowerState::Idle),
+            PowerState::Starting if server.since.elapsed() >= start_timeout => {
+                warn!("Server {} did not come up within {:?}", server_id, start_timeout);
+                server.set(PowerState::Idle)
+            }
+            _ => {}
+        }
+        server.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_AGE: Duration = Duration::from_secs(60);
+    const COOLDOWN: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn claims_one_query_per_max_age() {
+        let power = PowerTracker::new();
+        assert!(power.claim_query("lobby", MAX_AGE, COOLDOWN));
+        assert!(!power.claim_query("lobby", MAX_AGE, COOLDOWN));
+        assert!(power.claim_query("survival", MAX_AGE, COOLDOWN));
+        assert!(power.claim_query("lobby", Duration::ZERO, COOLDOWN));
+    }
+
+    #[test]
+    fn skips_queries_while_a_start_is_pending() {
+        let power = PowerTracker::new();
+        assert_eq!(power.begin_start("lobby", COOLDOWN), StartDecision::Send);
+        assert!(!power.claim_query("lobby", Duration::ZERO, COOLDOWN));
+        assert!(power.claim_query("lobby", Duration::ZERO, Duration::ZERO));
+
+        power.start_failed("lobby");
+        assert!(power.claim_query("lobby", Duration::ZERO, COOLDOWN));
+    }
+
+    #[test]
+    fn concurrent_starts_collapse_into_one() {
+        let power = PowerTracker::new();
+        let decisions: Vec<StartDecision> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8).map(|_| scope.spawn(|| power.begin_start("lobby", COOLDOWN))).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        assert_eq!(decisions.iter().filter(|d| **d == StartDecision::Send).count(), 1);
+        assert_eq!(decisions.iter().filter(|d| **d == StartDecision::InFlight).count(), 7);
+        assert_eq!(power.state("lobby"), PowerState::Starting);
+    }
+
+    #[test]
+    fn failed_start_keeps_the_cooldown() {
+        let power = PowerTracker::new();
+        assert_eq!(power.begin_start("lobby", COOLDOWN), StartDecision::Send);
+        power.start_failed("lobby");
+        assert_eq!(power.state("lobby"), PowerState::Idle);
+
+        match power.begin_start("lobby", COOLDOWN) {
+            StartDecision::CoolingDown(remaining) => {
+                assert!(remaining <= COOLDOWN && remaining > COOLDOWN - Duration::from_secs(5), "{:?}", remaining)
+            }
+            decision => panic!("expected a cooldown, got {:?}", decision),
+        }
+        assert_eq!(power.state("lobby"), PowerState::Idle);
+        assert_eq!(power.begin_start("lobby", Duration::ZERO), StartDecision::Send);
+    }
+
+    #[test]
+    fn gives_up_on_a_start_after_the_timeout() {
+        let power = PowerTracker::new();
+        power.begin_start("lobby", COOLDOWN);
+        assert_eq!(power.mark_unreachable("lobby", Duration::from_secs(180)), PowerState::Starting);
+        assert!(power.start_eta("lobby", Duration::from_secs(180)).is_some());
+        assert_eq!(power.mark_unreachable("lobby", Duration::ZERO), PowerState::Idle);
+        assert_eq!(power.start_eta("lobby", Duration::from_secs(180)), None);
+
+        power.mark_running("lobby");
+        assert_eq!(power.mark_unreachable("lobby", Duration::from_secs(180)), PowerState::Idle);
+        power.set_state("lobby", PowerState::Suspended);
+        assert_eq!(power.mark_unreachable("lobby", Duration::ZERO), PowerState::Suspended);
+    }
+}
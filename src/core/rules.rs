@@ -0,0 +1,320 @@
+use crate::core::config::{minute_of_day, parse_time_of_day, window_covers, MotdSlot};
+use crate::utils::packet::NextState;
+use crate::utils::protocol::VersionRange;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, ToSocketAddrs};
+
+/// One entry of `rules`. Rules are tried in order once the handshake (and the
+/// Login Start, for logins) has been read, and the first that matches decides.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    /// Shown in logs when the rule matches
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, rename = "match")]
+    pub conditions: Conditions,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+/// Every condition that is set must hold for a rule to match.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Conditions {
+    /// Hostname pattern, `*` matches any run of characters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Port the client says it connected to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<VersionRange>,
+    /// Client address ranges, any of which may match
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub source: Vec<IpNet>,
+    /// Username pattern. Never matches status pings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Whether the client announces a Forge mod list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forge: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<TimeWindow>,
+}
+
+/// A daily window in UTC, `HH:MM`. It may wrap past midnight.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimeWindow {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Forward to a backend that is not in the server list
+    Route(RouteTarget),
+    /// Turn logins away with a message. Status pings skip the rule.
+    Kick(String),
+    /// Answer status pings with a MOTD. Logins skip the rule.
+    Motd(MotdSlot),
+    /// Handle the connection as if it was made to another hostname
+    Redirect(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteTarget {
+    pub backend_server: String,
+    #[serde(default = "default_proxy_protocol")]
+    pub proxy_protocol: bool,
+}
+
+fn default_proxy_protocol() -> bool {
+    true
+}
+
+/// What the rules are matched against.
+#[derive(Debug, Clone)]
+pub struct Connection<'a> {
+    pub hostname: &'a str,
+    pub port: u16,
+    pub protocol: i32,
+    pub ip: IpAddr,
+    pub username: Option<&'a str>,
+    pub forge: bool,
+    pub next_state: &'a NextState,
+}
+
+impl Rule {
+    /// Name for logs, falling back to the rule's position.
+    pub fn label(&self, index: usize) -> String {
+        self.name.clone().unwrap_or_else(|| format!("rules[{}]", index))
+    }
+
+    fn matches(&self, connection: &Connection, minute: u32) -> bool {
+        let applies = match self.action {
+            Action::Kick(_) => *connection.next_state == NextState::Login,
+            Action::Motd(_) => *connection.next_state == NextState::Status,
+            Action::Route(_) | Action::Redirect(_) => true,
+        };
+        applies && self.conditions.hold(connection, minute)
+    }
+
+    /// Describes what is wrong with the rule at `at`, if anything.
+    pub fn problems(&self, at: &str) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Some(time) = &self.conditions.time {
+            for (field, value) in [("from", &time.from), ("until", &time.until)] {
+                if let Some(value) = value {
+                    if parse_time_of_day(value).is_none() {
+                        problems.push(format!("{}.match.time.{}: {:?} is not a HH:MM time", at, field, value));
+                    }
+                }
+            }
+        }
+        match &self.action {
+            Action::Route(target) => {
+                if let Err(e) = target.backend_server.to_socket_addrs() {
+                    problems.push(format!(
+                        "{}.route.backend_server {:?} cannot be resolved: {}",
+                        at, target.backend_server, e
+                    ));
+                }
+            }
+            Action::Motd(slot) => problems.extend(slot.problems(&format!("{}.motd", at))),
+            Action::Kick(_) | Action::Redirect(_) => {}
+        }
+        problems
+    }
+}
+
+impl Conditions {
+    /// Whether every condition holds for `connection` at `minute` past
+    /// midnight UTC.
+    fn hold(&self, connection: &Connection, minute: u32) -> bool {
+        if let Some(pattern) = &self.hostname {
+            if !glob_match(pattern, connection.hostname) {
+                return false;
+            }
+        }
+        if self.port.is_some_and(|port| port != connection.port) {
+            return false;
+        }
+        if self.protocol.as_ref().is_some_and(|range| !range.contains(connection.protocol)) {
+            return false;
+        }
+        if !self.source.is_empty() && !self.source.iter().any(|net| net.contains(&connection.ip)) {
+            return false;
+        }
+        if let Some(pattern) = &self.username {
+            if !connection.username.is_some_and(|username| glob_match(pattern, username)) {
+                return false;
+            }
+        }
+        if self.forge.is_some_and(|forge| forge != connection.forge) {
+            return false;
+        }
+        if let Some(time) = &self.time {
+            if !window_covers(time.from.as_deref(), time.until.as_deref(), minute) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The first rule matching `connection`, with its index.
+pub fn first_match<'a>(rules: &'a [Rule], connection: &Connection) -> Option<(usize, &'a Rule)> {
+    first_match_at(rules, connection, minute_of_day())
+}
+
+fn first_match_at<'a>(rules: &'a [Rule], connection: &Connection, minute: u32) -> Option<(usize, &'a Rule)> {
+    rules.iter().enumerate().find(|(_, rule)| rule.matches(connection, minute))
+}
+
+/// Case-insensitive match where `*` stands for any run of characters and `?`
+/// for a single one.
+fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let value: Vec<char> = value.to_lowercase().chars().collect();
+    let (mut p, mut v) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, v));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    v = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection<'a>(next_state: &'a NextState) -> Connection<'a> {
+        Connection {
+            hostname: "play.example.com",
+            port: 25565,
+            protocol: 767,
+            ip: "203.0.113.7".parse().unwrap(),
+            username: Some("Steve"),
+            forge: false,
+            next_state,
+        }
+    }
+
+    fn conditions(yaml: &str) -> Conditions {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("*.example.com", "play.example.com"));
+        assert!(glob_match("*.EXAMPLE.com", "Play.Example.COM"));
+        assert!(glob_match("pl?y.*", "play.example.com"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXXbYbZc"));
+        assert!(glob_match("**", "anything"));
+        assert!(!glob_match("*.example.com", "example.com"));
+        assert!(!glob_match("pl?y", "plaay"));
+        assert!(!glob_match("a*b", "aXbY"));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn checks_each_condition() {
+        let login = NextState::Login;
+        let status = NextState::Status;
+        let noon = 12 * 60;
+        let cases = [
+            ("hostname: '*.example.com'", true),
+            ("hostname: 'lobby.*'", false),
+            ("port: 25565", true),
+            ("port: 25566", false),
+            ("protocol: 1.21-1.21.4", true),
+            ("protocol: 1.8-1.12.2", false),
+            ("source: [203.0.113.0/24, 10.0.0.0/8]", true),
+            ("source: [198.51.100.0/24]", false),
+            ("username: 'st*'", true),
+            ("username: Alex", false),
+            ("forge: false", true),
+            ("forge: true", false),
+            ("time: { from: '08:00', until: '18:00' }", true),
+            ("time: { from: '18:00', until: '08:00' }", false),
+            ("{ port: 25565, username: Alex }", false),
+            ("{}", true),
+        ];
+        for (yaml, expected) in cases {
+            assert_eq!(conditions(yaml).hold(&connection(&login), noon), expected, "{}", yaml);
+        }
+
+        let ping = Connection { username: None, ..connection(&status) };
+        assert!(!conditions("username: '*'").hold(&ping, noon));
+        let ipv6 = Connection { ip: "2001:db8::1".parse().unwrap(), ..connection(&login) };
+        assert!(conditions("source: ['2001:db8::/32']").hold(&ipv6, noon));
+    }
+
+    #[test]
+    fn time_windows_may_cross_midnight() {
+        let login = NextState::Login;
+        let night = conditions("time: { from: '22:00', until: '06:00' }");
+        for (minute, expected) in [(21 * 60 + 59, false), (22 * 60, true), (0, true), (5 * 60 + 59, true), (6 * 60, false)] {
+            assert_eq!(night.hold(&connection(&login), minute), expected, "minute {}", minute);
+        }
+        let evening = conditions("time: { from: '18:00' }");
+        assert!(evening.hold(&connection(&login), 23 * 60 + 59));
+        assert!(!evening.hold(&connection(&login), 17 * 60));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules: Vec<Rule> = serde_yaml::from_str(
+            "
+- name: kick-alex
+  match: { username: Alex }
+  kick: Go away
+- match: { hostname: '*.example.com' }
+  redirect: lobby.example.com
+- route: { backend_server: '127.0.0.1:25566' }
+",
+        )
+        .unwrap();
+        let login = NextState::Login;
+        let status = NextState::Status;
+
+        let (index, rule) = first_match_at(&rules, &connection(&login), 0).unwrap();
+        assert_eq!((index, rule.label(index)), (1, "rules[1]".to_string()));
+        assert!(matches!(&rule.action, Action::Redirect(host) if host == "lobby.example.com"));
+
+        let alex = Connection { username: Some("alex"), ..connection(&login) };
+        let (index, rule) = first_match_at(&rules, &alex, 0).unwrap();
+        assert_eq!((index, rule.label(index)), (0, "kick-alex".to_string()));
+
+        // Kick rules skip status pings
+        let ping = Connection { username: None, hostname: "other.net", ..connection(&status) };
+        let (index, rule) = first_match_at(&rules, &ping, 0).unwrap();
+        assert_eq!(index, 2);
+        assert!(matches!(&rule.action, Action::Route(target) if target.proxy_protocol));
+
+        assert!(first_match_at(&rules[..2], &ping, 0).is_none());
+    }
+}
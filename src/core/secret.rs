@@ -0,0 +1,81 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// A credential that is printed as `<redacted>` by `Debug`.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.trim().is_empty()
+    }
+
+    /// A placeholder to serialize in place of this secret, empty if it is.
+    pub fn redacted(&self) -> Secret {
+        if self.is_empty() {
+            self.clone()
+        } else {
+            Secret::new("<redacted>")
+        }
+    }
+
+    /// Picks the first configured source: the inline value, then the
+    /// environment variable, then the file. Returns `None` if none is set.
+    pub fn resolve(inline: Option<&Secret>, env: Option<&str>, file: Option<&Path>) -> Result<Option<Secret>> {
+        if let Some(secret) = inline.filter(|s| !s.is_empty()) {
+            return Ok(Some(secret.clone()));
+        }
+        if let Some(var) = env {
+            let value = std::env::var(var).map_err(|_| anyhow!("environment variable {} is not set", var))?;
+            return Ok(Some(Secret::new(value.trim())));
+        }
+        if let Some(path) = file {
+            let value = fs::read_to_string(path).map_err(|e| anyhow!("cannot read {}: {}", path.display(), e))?;
+            warn_if_world_readable(path);
+            return Ok(Some(Secret::new(value.trim())));
+        }
+        Ok(None)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "\"\"")
+        } else {
+            write!(f, "<redacted>")
+        }
+    }
+}
+
+/// Warns when a file holding a credential can be read by every local user.
+#[cfg(unix)]
+pub fn warn_if_world_readable(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(metadata) = fs::metadata(path) {
+        let mode = metadata.permissions().mode();
+        if mode & 0o004 != 0 {
+            warn!(
+                "{} contains an API key but is world-readable (mode {:o}), consider `chmod 600`",
+                path.display(),
+                mode & 0o777
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn warn_if_world_readable(_path: &Path) {}
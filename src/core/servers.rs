@@ -1,10 +1,17 @@
+use crate::core::controller::PowerConfig;
 use serde::{Deserialize, Serialize};
-use std::fs;
+use crate::core::config::Config;
+use crate::core::format::{self, Format};
+use crate::core::secret;
+use crate::core::validate::validate;
+use crate::utils::protocol::VersionRange;
 use anyhow::Result;
-use serde_json;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use std::sync::atomic::{AtomicUsize, Ordering};
-#[macro_use]
-use log::{info, warn};
+use std::sync::{Arc, RwLock};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServerEntry {
@@ -12,117 +19,345 @@ pub struct ServerEntry {
     pub hostnames: Vec<String>,
     #[serde(rename = "backend_server")]
     pub backend_server: String,
+    /// Send a PROXY protocol header ahead of the handshake
+    #[serde(default = "default_proxy_protocol", skip_serializing_if = "is_true")]
+    pub proxy_protocol: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub power: Option<PowerConfig>,
+    /// Overrides the global `idle_stop.enabled` for this server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_stop: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_uptime_secs: Option<u64>,
+    /// Backends for particular client versions, tried in order before
+    /// `backend_server`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub version_routes: Vec<VersionRoute>,
+    /// Protocol versions the backend accepts, e.g. `1.8-1.21.4`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub versions: Option<VersionRange>,
+    /// Turn every player away with the maintenance message
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub maintenance: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub messages: Option<ServerMessages>,
     #[serde(skip)]
     pub player_count: AtomicUsize,
 }
 
+/// Sends clients whose protocol is in `versions` to another backend.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VersionRoute {
+    pub versions: VersionRange,
+    pub backend_server: String,
+    /// Overrides the entry's `proxy_protocol` for this backend
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_protocol: Option<bool>,
+}
+
+fn default_proxy_protocol() -> bool {
+    true
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// Replaces the global `offline_server` messages for one server. Unset fields
+/// fall back to the global ones.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerMessages {
+    /// Description shown while the server is offline
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kick_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub starting_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maintenance_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unsupported_version_message: Option<String>,
+    /// Image shown in the server list, scaled to 64x64
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub favicon: Option<PathBuf>,
+    /// Version name shown next to the offline MOTD
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_players: Option<u32>,
+}
+
+impl ServerEntry {
+    fn version_route(&self, protocol: i32) -> Option<&VersionRoute> {
+        self.version_routes.iter().find(|route| route.versions.contains(protocol))
+    }
+
+    /// Backend that clients speaking `protocol` are sent to, for status pings
+    /// and logins alike.
+    pub fn backend_for(&self, protocol: i32) -> &str {
+        self.version_route(protocol)
+            .map_or(&self.backend_server, |route| &route.backend_server)
+    }
+
+    /// Every backend the server can be reached at, `backend_server` first.
+    pub fn backends(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.backend_server.as_str())
+            .chain(self.version_routes.iter().map(|route| route.backend_server.as_str()))
+    }
+
+    pub fn proxy_protocol_for(&self, protocol: i32) -> bool {
+        self.version_route(protocol)
+            .and_then(|route| route.proxy_protocol)
+            .unwrap_or(self.proxy_protocol)
+    }
+
+    pub fn has_inline_api_key(&self) -> bool {
+        match &self.power {
+            Some(PowerConfig::Pterodactyl(credentials)) => credentials.api_key.as_ref().is_some_and(|k| !k.is_empty()),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+impl ServerEntry {
+    /// Entry `id` reachable at `{id}.example`, with `power` as its controller.
+    pub fn for_test(id: &str, power: serde_json::Value) -> Self {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "hostnames": [format!("{}.example", id)],
+            "backend_server": "127.0.0.1:25565",
+            "power": power,
+        }))
+        .unwrap()
+    }
+}
+
 impl Clone for ServerEntry {
     fn clone(&self) -> Self {
         Self {
             id: self.id.clone(),
             hostnames: self.hostnames.clone(),
             backend_server: self.backend_server.clone(),
+            proxy_protocol: self.proxy_protocol,
+            power: self.power.clone(),
+            auto_stop: self.auto_stop,
+            idle_timeout_secs: self.idle_timeout_secs,
+            min_uptime_secs: self.min_uptime_secs,
+            version_routes: self.version_routes.clone(),
+            versions: self.versions.clone(),
+            maintenance: self.maintenance,
+            messages: self.messages.clone(),
             player_count: AtomicUsize::new(self.player_count.load(Ordering::Relaxed)),
         }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct ServersFile {
-    servers: Vec<ServerEntry>
+pub struct ServersFile {
+    pub servers: Vec<ServerEntry>
+}
+
+/// Where the entries came from.
+#[derive(Debug, Clone)]
+enum Source {
+    Inline,
+    File(PathBuf),
+    Directory(PathBuf),
 }
 
+/// Clones share the same entries, so player counts are visible to every
+/// connection and background task.
 #[derive(Debug)]
 pub struct Servers {
-    entries: Vec<ServerEntry>,
+    entries: Arc<RwLock<Vec<ServerEntry>>>,
+    /// Servers reported by a discovery provider such as Docker
+    discovered: Arc<RwLock<Vec<ServerEntry>>>,
+    source: Source,
 }
 
 impl Clone for Servers {
     fn clone(&self) -> Self {
         Self {
-            entries: self.entries.clone()
+            entries: self.entries.clone(),
+            discovered: self.discovered.clone(),
+            source: self.source.clone(),
         }
     }
 }
 
 impl Servers {
-    pub fn load() -> Result<Self> {
-        let content = fs::read_to_string("servers.json")?;
-        let mut servers_file: ServersFile = serde_json::from_str(&content)?;
-        
+    /// Loads the servers defined inline in the config, or else those in
+    /// `servers_dir`, or else those in `servers_file`.
+    pub fn load(config: &Config) -> Result<Self> {
+        match (&config.servers, &config.servers_dir) {
+            (Some(servers), _) => Ok(Self::from_entries(servers.clone(), Source::Inline)),
+            (None, Some(dir)) => Self::load_dir(dir),
+            (None, None) => Self::load_from(&config.servers_file),
+        }
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self> {
+        let servers_file: ServersFile = format::read_file(path)?;
+        Ok(Self::from_entries(servers_file.servers, Source::File(path.to_path_buf())))
+    }
+
+    /// Loads one server per file from `dir`. Files that fail to parse or
+    /// conflict with an earlier file are logged and skipped.
+    pub fn load_dir(dir: &Path) -> Result<Self> {
+        let entries = read_dir_entries(dir)?;
+        info!("Loaded {} server(s) from {}", entries.len(), dir.display());
+        Ok(Self::from_entries(entries, Source::Directory(dir.to_path_buf())))
+    }
+
+    fn from_entries(mut entries: Vec<ServerEntry>, source: Source) -> Self {
         // Initialize player counts to 0
-        for server in &mut servers_file.servers {
+        for server in &mut entries {
             server.player_count = AtomicUsize::new(0);
         }
-        
-        Ok(Self { 
-            entries: servers_file.servers 
-        })
+
+        Self {
+            entries: Arc::new(RwLock::new(entries)),
+            discovered: Arc::new(RwLock::new(Vec::new())),
+            source,
+        }
+    }
+
+    /// The file or directory the servers were loaded from, `None` when they
+    /// are inline in the config.
+    pub fn path(&self) -> Option<&Path> {
+        match &self.source {
+            Source::Inline => None,
+            Source::File(path) | Source::Directory(path) => Some(path),
+        }
+    }
+
+    /// Polls the server directory and reloads it whenever a file is created,
+    /// modified or deleted. Returns immediately for other sources.
+    pub async fn watch(self, config: Config, interval: Duration) {
+        let Source::Directory(dir) = &self.source else { return };
+        let mut last = dir_snapshot(dir);
+        loop {
+            tokio::time::sleep(interval).await;
+            let current = dir_snapshot(dir);
+            if current == last {
+                continue;
+            }
+            last = current;
+            if let Err(e) = self.reload(&config, dir) {
+                error!("Failed to reload {}: {}", dir.display(), e);
+            }
+        }
+    }
+
+    /// Reads `dir` again and swaps in its entries, keeping the player count of
+    /// every server whose id is unchanged. The current set stays in place if
+    /// the new one does not pass validation.
+    fn reload(&self, config: &Config, dir: &Path) -> Result<()> {
+        let entries = read_dir_entries(dir)?;
+        let problems = validate(config, &entries, &dir.display().to_string());
+        if !problems.is_empty() {
+            for problem in &problems {
+                error!("{}", problem);
+            }
+            return Err(anyhow!(
+                "{} configuration problem(s), keeping the previous {} server(s)",
+                problems.len(),
+                self.entries.read().unwrap().len()
+            ));
+        }
+        swap_entries(&self.entries, entries);
+        Ok(())
+    }
+
+    /// Replaces the servers found by a discovery provider. They are looked up
+    /// after the configured servers, which win any hostname conflict.
+    pub fn set_discovered(&self, entries: Vec<ServerEntry>) {
+        for entry in &entries {
+            for hostname in &entry.hostnames {
+                if let Some(other) = self.entries.read().unwrap().iter().find(|s| s.hostnames.contains(hostname)) {
+                    warn!("Discovered server {} uses hostname {} of server {}, ignoring it", entry.id, hostname, other.id);
+                }
+            }
+        }
+        swap_entries(&self.discovered, entries);
+    }
+
+    /// Runs `f` on the first server that `matches`, configured servers first.
+    fn find_map<R>(&self, matches: impl Fn(&ServerEntry) -> bool, f: impl FnOnce(&ServerEntry) -> R) -> Option<R> {
+        if let Some(server) = self.entries.read().unwrap().iter().find(|s| matches(s)) {
+            return Some(f(server));
+        }
+        self.discovered.read().unwrap().iter().find(|s| matches(s)).map(f)
     }
 
     pub fn increment_player_count(&self, hostname: &str) -> Option<usize> {
-        if let Some(server) = self.entries
-            .iter()
-            .find(|s| s.hostnames.contains(&hostname.to_string())) {
+        self.find_map(|s| s.hostnames.iter().any(|h| h == hostname), |server| {
             let new_count = server.player_count.fetch_add(1, Ordering::SeqCst) + 1;
             info!("Player joined {} - Current players: {}", hostname, new_count);
-            Some(new_count)
-        } else {
-            None
-        }
+            new_count
+        })
     }
 
     pub fn decrement_player_count(&self, hostname: &str) -> Option<usize> {
-        if let Some(server) = self.entries
-            .iter()
-            .find(|s| s.hostnames.contains(&hostname.to_string())) {
+        let result = self.find_map(|s| s.hostnames.iter().any(|h| h == hostname), |server| {
             let current = server.player_count.load(Ordering::SeqCst);
             if current > 0 {
                 let new_count = server.player_count.fetch_sub(1, Ordering::SeqCst) - 1;
                 info!("Player left {} - Current players: {}", hostname, new_count);
-                Some(new_count)
+                new_count
             } else {
                 warn!("Attempted to decrement player count below 0 for {}", hostname);
-                Some(0)
+                0
             }
-        } else {
+        });
+        if result.is_none() {
             warn!("Attempted to decrement player count for unknown server: {}", hostname);
-            None
         }
+        result
     }
 
     pub fn get_player_count(&self, hostname: &str) -> Option<usize> {
-        self.entries
-            .iter()
-            .find(|s| s.hostnames.contains(&hostname.to_string()))
-            .map(|s| s.player_count.load(Ordering::SeqCst))
+        self.find_map(|s| s.hostnames.iter().any(|h| h == hostname), |s| s.player_count.load(Ordering::SeqCst))
     }
 
     pub fn get_by_hostname(&self, hostname: &str) -> Option<ServerEntry> {
-        self.entries
-            .iter()
-            .find(|s| s.hostnames.contains(&hostname.to_string()))
-            .cloned()
+        self.find_map(|s| s.hostnames.iter().any(|h| h == hostname), ServerEntry::clone)
+    }
+
+    pub fn get_by_id(&self, id: &str) -> Option<ServerEntry> {
+        self.find_map(|s| s.id == id, ServerEntry::clone)
     }
 
     pub fn save(&self) -> Result<()> {
+        let path = match &self.source {
+            Source::File(path) => path,
+            Source::Inline => return Err(anyhow!("Servers are defined inline in the config and cannot be saved")),
+            Source::Directory(dir) => return Err(anyhow!("Servers are managed through {} and cannot be saved", dir.display())),
+        };
         let servers_file = ServersFile {
-            servers: self.entries.clone()
+            servers: self.entries.read().unwrap().to_vec()
         };
-        let json = serde_json::to_string_pretty(&servers_file)?;
-        fs::write("servers.json", json)?;
-        Ok(())
+        format::write_file(path, &servers_file)
     }
 
     pub fn add_server(&self, entry: ServerEntry) -> Result<()> {
-        let mut entries = self.entries.clone();
+        let mut entries = self.list_servers();
         entries.push(entry);
         self.save()?;
         Ok(())
     }
 
     pub fn remove_server(&self, hostname: &str) -> Result<bool> {
-        let mut entries = self.entries.clone();
+        let mut entries = self.list_servers();
         let len = entries.len();
         entries.retain(|s| s.hostnames.contains(&hostname.to_string()));
         let removed = entries.len() != len;
@@ -133,7 +368,7 @@ impl Servers {
     }
 
     pub fn update_server(&self, hostname: &str, new_entry: ServerEntry) -> Result<bool> {
-        let mut entries = self.entries.clone();
+        let mut entries = self.list_servers();
         if let Some(entry) = entries.iter_mut().find(|s| s.hostnames.contains(&hostname.to_string())) {
             *entry = new_entry;
             self.save()?;
@@ -143,7 +378,184 @@ impl Servers {
         }
     }
 
+    /// Configured servers followed by discovered ones.
     pub fn list_servers(&self) -> Vec<ServerEntry> {
-        self.entries.clone()
+        let mut servers = self.entries.read().unwrap().to_vec();
+        servers.extend(self.discovered.read().unwrap().iter().cloned());
+        servers
+    }
+}
+
+fn swap_entries(target: &RwLock<Vec<ServerEntry>>, mut entries: Vec<ServerEntry>) {
+    let mut current = target.write().unwrap();
+    for entry in &mut entries {
+        let previous = current.iter().find(|s| s.id == entry.id);
+        let count = previous.map(|s| s.player_count.load(Ordering::SeqCst)).unwrap_or(0);
+        entry.player_count = AtomicUsize::new(count);
+        match previous {
+            None => info!("Server {} added", entry.id),
+            Some(previous)
+                if previous.hostnames != entry.hostnames
+                    || previous.backend_server != entry.backend_server
+                    || previous.version_routes != entry.version_routes =>
+            {
+                info!("Server {} updated", entry.id)
+            }
+            _ => {}
+        }
+    }
+    for removed in current.iter().filter(|s| !entries.iter().any(|e| e.id == s.id)) {
+        info!("Server {} removed", removed.id);
+    }
+    *current = entries;
+}
+
+/// Files in the server directory that look like server definitions, sorted
+/// so conflicts are resolved the same way on every load.
+fn dir_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| anyhow!("{}: {}", dir.display(), e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file())
+        .filter(|path| !path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with('.')))
+        .filter(|path| Format::from_path(path).is_ok())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn dir_snapshot(dir: &Path) -> BTreeMap<PathBuf, (Option<SystemTime>, u64)> {
+    dir_files(dir)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|path| {
+            let metadata = fs::metadata(&path).ok()?;
+            Some((path, (metadata.modified().ok(), metadata.len())))
+        })
+        .collect()
+}
+
+fn read_dir_entries(dir: &Path) -> Result<Vec<ServerEntry>> {
+    let mut entries: Vec<ServerEntry> = Vec::new();
+    let mut owners: HashMap<String, PathBuf> = HashMap::new();
+    for file in dir_files(dir)? {
+        let entry: ServerEntry = match format::read_file(&file) {
+            Ok(entry) => entry,
+            Err(e) => {
+                error!("{}", e);
+                continue;
+            }
+        };
+        let id_key = format!("id {:?}", entry.id);
+        let conflict = std::iter::once(id_key.clone())
+            .chain(entry.hostnames.iter().map(|h| format!("hostname {:?}", h.to_lowercase())))
+            .find_map(|key| owners.get(&key).map(|owner| (key, owner.clone())));
+        if let Some((key, owner)) = conflict {
+            error!("{}: {} is already defined in {}, skipping", file.display(), key, owner.display());
+            continue;
+        }
+        if entry.has_inline_api_key() {
+            secret::warn_if_world_readable(&file);
+        }
+        owners.insert(id_key, file.clone());
+        for hostname in &entry.hostnames {
+            owners.insert(format!("hostname {:?}", hostname.to_lowercase()), file.clone());
+        }
+        entries.push(entry);
     }
-} 
\ No newline at end of file
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("madproxy-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn write(&self, file: &str, text: &str) {
+            fs::write(self.0.join(file), text).unwrap();
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn entry_json(id: &str, hostname: &str, backend: &str) -> String {
+        format!(r#"{{"id": "{}", "hostnames": ["{}"], "backend_server": "{}"}}"#, id, hostname, backend)
+    }
+
+    fn ids(servers: &Servers) -> Vec<String> {
+        servers.list_servers().into_iter().map(|s| s.id).collect()
+    }
+
+    fn config() -> Config {
+        Config { auto_start: false, ..Config::default() }
+    }
+
+    #[test]
+    fn skips_files_that_conflict_or_fail_to_parse() {
+        let dir = TempDir::new("conflicts");
+        dir.write("a.json", &entry_json("lobby", "lobby.example", "127.0.0.1:25565"));
+        dir.write("b.json", &entry_json("lobby", "other.example", "127.0.0.1:25566"));
+        dir.write("c.json", &entry_json("survival", "LOBBY.example", "127.0.0.1:25567"));
+        dir.write("d.yml", "id: creative\nhostnames: [creative.example]\nbackend_server: 127.0.0.1:25568\n");
+        dir.write("e.json", "{ not json");
+        dir.write(".hidden.json", &entry_json("hidden", "hidden.example", "127.0.0.1:25569"));
+        dir.write("notes.txt", "not a server");
+
+        let servers = Servers::load_dir(&dir.0).unwrap();
+        assert_eq!(ids(&servers), ["lobby", "creative"]);
+        assert_eq!(servers.get_by_hostname("lobby.example").unwrap().backend_server, "127.0.0.1:25565");
+    }
+
+    #[test]
+    fn reloads_and_keeps_player_counts() {
+        let dir = TempDir::new("reload");
+        dir.write("lobby.json", &entry_json("lobby", "lobby.example", "127.0.0.1:25565"));
+        dir.write("survival.json", &entry_json("survival", "survival.example", "127.0.0.1:25566"));
+        let servers = Servers::load_dir(&dir.0).unwrap();
+        servers.increment_player_count("lobby.example");
+        servers.increment_player_count("lobby.example");
+        servers.increment_player_count("survival.example");
+
+        dir.write("lobby.json", &entry_json("lobby", "hub.example", "127.0.0.1:25570"));
+        fs::remove_file(dir.0.join("survival.json")).unwrap();
+        dir.write("creative.json", &entry_json("creative", "creative.example", "127.0.0.1:25567"));
+        servers.reload(&config(), &dir.0).unwrap();
+
+        assert_eq!(ids(&servers), ["creative", "lobby"]);
+        assert_eq!(servers.get_player_count("hub.example"), Some(2));
+        assert_eq!(servers.get_player_count("creative.example"), Some(0));
+        assert_eq!(servers.get_by_id("lobby").unwrap().backend_server, "127.0.0.1:25570");
+        assert!(servers.get_by_hostname("survival.example").is_none());
+    }
+
+    #[test]
+    fn keeps_the_previous_set_when_validation_fails() {
+        let dir = TempDir::new("invalid");
+        dir.write("lobby.json", &entry_json("lobby", "lobby.example", "127.0.0.1:25565"));
+        let servers = Servers::load_dir(&dir.0).unwrap();
+        servers.increment_player_count("lobby.example");
+
+        dir.write("lobby.json", r#"{"id": "lobby", "hostnames": [], "backend_server": "127.0.0.1:25565"}"#);
+        let err = servers.reload(&config(), &dir.0).unwrap_err();
+        assert_eq!(err.to_string(), "1 configuration problem(s), keeping the previous 1 server(s)");
+        assert_eq!(servers.get_player_count("lobby.example"), Some(1));
+
+        dir.write("lobby.json", &entry_json("lobby", "lobby.example", "127.0.0.1:25566"));
+        servers.reload(&config(), &dir.0).unwrap();
+        assert_eq!(servers.get_by_id("lobby").unwrap().backend_server, "127.0.0.1:25566");
+        assert_eq!(servers.get_player_count("lobby.example"), Some(1));
+    }
+}
@@ -0,0 +1,244 @@
+use crate::core::config::TcpShieldSettings;
+use anyhow::Result;
+use base64::Engine;
+use rsa::pkcs1v15::{Signature, VerifyingKey};
+use rsa::pkcs8::DecodePublicKey;
+use rsa::signature::Verifier as _;
+use rsa::RsaPublicKey;
+use sha2::Sha512;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Separates the fields of a TCPShield envelope
+const SEPARATOR: &str = "///";
+/// Separates extra handshake data appended to the signature
+const EXTRA_SEPARATOR: &str = "%%%";
+
+/// What a TCPShield edge says about the client, once verified.
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    /// The hostname the client connected to
+    pub hostname: String,
+    /// The client's real address
+    pub client: SocketAddr,
+}
+
+/// Checks `host///ip:port///timestamp///signature` addresses written by
+/// TCPShield edges. The signature is SHA512withRSA over the address exactly as
+/// sent up to the last separator, Forge marker included when the edge put the
+/// envelope after it.
+#[derive(Debug, Clone)]
+pub struct TcpShield {
+    settings: TcpShieldSettings,
+    key: VerifyingKey<Sha512>,
+}
+
+/// Whether `address` carries an envelope at all.
+pub fn has_envelope(address: &str) -> bool {
+    address.contains(SEPARATOR)
+}
+
+/// The hostname in front of an envelope, for when envelopes are not trusted.
+pub fn strip_envelope(address: &str) -> &str {
+    address.split(SEPARATOR).next().unwrap_or(address)
+}
+
+/// Reads `ip:port`, where an IPv6 address may come without brackets.
+fn parse_client(client: &str) -> Option<SocketAddr> {
+    if let Ok(client) = client.parse() {
+        return Some(client);
+    }
+    let (ip, port) = client.rsplit_once(':')?;
+    Some(SocketAddr::new(ip.parse().ok()?, port.parse().ok()?))
+}
+
+impl TcpShield {
+    pub fn new(settings: &TcpShieldSettings) -> Result<Self> {
+        if settings.trusted_sources.is_empty() {
+            return Err(anyhow!("trusted_sources is empty, list the addresses of the TCPShield edges"));
+        }
+        let key = settings.public_key()?;
+        let key = key.trim();
+        let key = if key.starts_with("-----BEGIN") {
+            RsaPublicKey::from_public_key_pem(key)
+        } else {
+            let der = base64::engine::general_purpose::STANDARD
+                .decode(key.split_whitespace().collect::<String>())
+                .map_err(|e| anyhow!("tcpshield public key is not base64: {}", e))?;
+            RsaPublicKey::from_public_key_der(&der)
+        }
+        .map_err(|e| anyhow!("tcpshield public key is invalid: {}", e))?;
+        Ok(Self {
+            settings: settings.clone(),
+            key: VerifyingKey::new(key),
+        })
+    }
+
+    /// Verifies the envelope in `address`, the handshake address exactly as
+    /// the client sent it, on a connection from `peer`.
+    pub fn verify(&self, address: &str, peer: IpAddr) -> Result<Envelope> {
+        if !self.settings.trusted_sources.iter().any(|net| net.contains(&peer)) {
+            return Err(anyhow!("{} is not a trusted TCPShield source", peer));
+        }
+
+        let fields: Vec<&str> = address.splitn(4, SEPARATOR).collect();
+        let [hostname, client, timestamp, signature] = fields[..] else {
+            return Err(anyhow!("malformed TCPShield envelope"));
+        };
+        // Extra data or a Forge marker may follow the signature
+        let signature = signature.split('\0').next().unwrap_or(signature);
+        let signature = signature.split(EXTRA_SEPARATOR).next().unwrap_or(signature);
+        let signed = &address[..hostname.len() + client.len() + timestamp.len() + 2 * SEPARATOR.len()];
+        let client = parse_client(client)
+            .ok_or_else(|| anyhow!("invalid client address {:?} in TCPShield envelope", client))?;
+        let timestamp: i64 = timestamp
+            .parse()
+            .map_err(|_| anyhow!("invalid timestamp {:?} in TCPShield envelope", timestamp))?;
+
+        let signature = base64::engine::general_purpose::STANDARD
+            .decode(signature)
+            .ok()
+            .and_then(|bytes| Signature::try_from(bytes.as_slice()).ok())
+            .ok_or_else(|| anyhow!("unreadable TCPShield signature"))?;
+        self.key
+            .verify(signed.as_bytes(), &signature)
+            .map_err(|_| anyhow!("TCPShield signature does not match"))?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        let skew = (now - timestamp).unsigned_abs();
+        if skew > self.settings.max_clock_skew_secs {
+            return Err(anyhow!("TCPShield envelope is {}s off the local clock", skew));
+        }
+
+        Ok(Envelope {
+            // Only now that it is verified, drop a Forge marker in front of the envelope
+            hostname: hostname.split('\0').next().unwrap_or(hostname).to_string(),
+            client,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::pkcs1v15::SigningKey;
+    use rsa::pkcs8::{EncodePublicKey, LineEnding};
+    use rsa::signature::{SignatureEncoding, Signer};
+    use rsa::RsaPrivateKey;
+    use std::sync::OnceLock;
+
+    const EDGE: &str = "10.0.0.7";
+
+    /// Generating a key is slow, so every test shares one.
+    fn key() -> &'static RsaPrivateKey {
+        static KEY: OnceLock<RsaPrivateKey> = OnceLock::new();
+        KEY.get_or_init(|| RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap())
+    }
+
+    fn shield() -> TcpShield {
+        let settings = TcpShieldSettings {
+            enabled: true,
+            public_key: Some(key().to_public_key().to_public_key_pem(LineEnding::LF).unwrap()),
+            trusted_sources: vec!["10.0.0.0/24".parse().unwrap()],
+            ..TcpShieldSettings::default()
+        };
+        TcpShield::new(&settings).unwrap()
+    }
+
+    fn now() -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+    }
+
+    /// Signs `host///client///timestamp` as an edge would and appends the signature.
+    fn envelope(host: &str, client: &str, timestamp: i64) -> String {
+        let signed = format!("{}///{}///{}", host, client, timestamp);
+        let signature = SigningKey::<Sha512>::new(key().clone()).sign(signed.as_bytes());
+        let signature = base64::engine::general_purpose::STANDARD.encode(signature.to_bytes());
+        format!("{}///{}", signed, signature)
+    }
+
+    fn verify(address: &str) -> Result<Envelope> {
+        shield().verify(address, EDGE.parse().unwrap())
+    }
+
+    #[test]
+    fn accepts_a_valid_envelope() {
+        let envelope = verify(&envelope("play.example.com", "203.0.113.9:5555", now())).unwrap();
+        assert_eq!(envelope.hostname, "play.example.com");
+        assert_eq!(envelope.client, "203.0.113.9:5555".parse().unwrap());
+    }
+
+    #[test]
+    fn rejects_a_bad_signature() {
+        let tampered = envelope("play.example.com", "203.0.113.9:5555", now()).replacen("203.0.113.9", "203.0.113.8", 1);
+        let error = verify(&tampered).unwrap_err().to_string();
+        assert!(error.contains("does not match"), "{}", error);
+        let garbled = format!("play.example.com///203.0.113.9:5555///{}///not base64!", now());
+        assert!(verify(&garbled).is_err());
+        assert!(verify("play.example.com///203.0.113.9:5555").is_err());
+    }
+
+    #[test]
+    fn rejects_stale_and_future_timestamps() {
+        for skew in [-60, 60] {
+            let error = verify(&envelope("play.example.com", "203.0.113.9:5555", now() + skew)).unwrap_err().to_string();
+            assert!(error.contains("off the local clock"), "{}", error);
+        }
+        assert!(verify(&envelope("play.example.com", "203.0.113.9:5555", now() - 3)).is_ok());
+    }
+
+    #[test]
+    fn only_trusts_listed_sources() {
+        let address = envelope("play.example.com", "203.0.113.9:5555", now());
+        let error = shield().verify(&address, "198.51.100.1".parse().unwrap()).unwrap_err().to_string();
+        assert!(error.contains("not a trusted"), "{}", error);
+
+        let settings = TcpShieldSettings {
+            enabled: true,
+            public_key: Some(key().to_public_key().to_public_key_pem(LineEnding::LF).unwrap()),
+            ..TcpShieldSettings::default()
+        };
+        let error = TcpShield::new(&settings).unwrap_err().to_string();
+        assert!(error.contains("trusted_sources"), "{}", error);
+    }
+
+    #[test]
+    fn reads_ipv6_clients() {
+        let expected: SocketAddr = "[2001:db8::1]:5555".parse().unwrap();
+        for client in ["[2001:db8::1]:5555", "2001:db8::1:5555"] {
+            assert_eq!(verify(&envelope("play.example.com", client, now())).unwrap().client, expected, "{}", client);
+        }
+    }
+
+    #[test]
+    fn ignores_extra_data_after_the_signature() {
+        let address = format!("{}%%%{{\"extra\":true}}", envelope("play.example.com", "203.0.113.9:5555", now()));
+        assert_eq!(verify(&address).unwrap().hostname, "play.example.com");
+    }
+
+    #[test]
+    fn verifies_envelopes_after_a_forge_marker() {
+        // The edge signs the address as the client sent it, marker included
+        let address = envelope("play.example.com\0FML2\0", "203.0.113.9:5555", now());
+        let verified = verify(&address).unwrap();
+        assert_eq!(verified.hostname, "play.example.com");
+        assert_eq!(verified.client, "203.0.113.9:5555".parse().unwrap());
+
+        // A signature over the bare hostname does not cover the marker
+        let bare = envelope("play.example.com", "203.0.113.9:5555", now());
+        let moved = bare.replacen("play.example.com", "play.example.com\0FML2\0", 1);
+        assert!(verify(&moved).is_err());
+
+        // A marker after the signature is not part of it
+        let trailing = format!("{}\0FML\0", envelope("play.example.com", "203.0.113.9:5555", now()));
+        assert!(verify(&trailing).is_ok());
+    }
+
+    #[test]
+    fn strips_untrusted_envelopes() {
+        assert!(has_envelope("a.example///1.2.3.4:5///6///sig"));
+        assert!(!has_envelope("a.example"));
+        assert_eq!(strip_envelope("a.example///1.2.3.4:5///6///sig"), "a.example");
+        assert_eq!(strip_envelope("a.example"), "a.example");
+    }
+}
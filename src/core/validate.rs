@@ -0,0 +1,142 @@
+use crate::core::config::Config;
+use crate::core::controller::{validate_controllers, PowerConfig};
+use crate::core::servers::ServerEntry;
+use crate::core::tcpshield::TcpShield;
+use crate::utils::favicon;
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
+
+/// Panel link written by `Config::default`
+const PLACEHOLDER_PANEL_LINK: &str = "https://your.panel.link";
+
+/// Checks a loaded configuration and server list for problems that parse
+/// fine but would break at runtime. `source` names where the servers were
+/// loaded from. Returns every problem found.
+pub fn validate(config: &Config, servers: &[ServerEntry], source: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if config.listen_addr.to_socket_addrs().map(|mut a| a.next().is_none()).unwrap_or(true) {
+        problems.push(format!("config: listen_addr: {:?} is not a valid address", config.listen_addr));
+    }
+
+    if let Some(path) = &config.offline_server.favicon {
+        if let Err(e) = favicon::load(path) {
+            problems.push(format!("config: offline_server.favicon: {}", e));
+        }
+    }
+
+    problems.extend(config.unknown_host.motd.problems("config: unknown_host.motd"));
+    problems.extend(config.offline_server.motd.problems("config: offline_server.motd"));
+    if let Some(online_motd) = &config.online_motd {
+        problems.extend(online_motd.problems("config: online_motd"));
+    }
+
+    for (index, rule) in config.rules.iter().enumerate() {
+        problems.extend(rule.problems(&format!("config: rules[{}]", index)));
+    }
+
+    if config.tcpshield.enabled {
+        if let Err(e) = TcpShield::new(&config.tcpshield) {
+            problems.push(format!("config: tcpshield: {}", e));
+        }
+    }
+
+    let mut ids: HashMap<&str, usize> = HashMap::new();
+    let mut hostnames: HashMap<String, &str> = HashMap::new();
+    for (index, server) in servers.iter().enumerate() {
+        let at = format!("{}: servers[{}] ({})", source, index, server.id);
+
+        if let Some(previous) = ids.insert(&server.id, index) {
+            problems.push(format!("{}: id {:?} is already used by servers[{}]", at, server.id, previous));
+        }
+        if server.hostnames.is_empty() {
+            problems.push(format!("{}: hostnames is empty", at));
+        }
+        for hostname in &server.hostnames {
+            if let Some(other) = hostnames.insert(hostname.to_lowercase(), &server.id) {
+                problems.push(format!("{}: hostname {:?} is also used by server {}", at, hostname, other));
+            }
+        }
+        if let Err(e) = server.backend_server.to_socket_addrs() {
+            problems.push(format!("{}: backend_server {:?} cannot be resolved: {}", at, server.backend_server, e));
+        }
+        for (route, version_route) in server.version_routes.iter().enumerate() {
+            if let Err(e) = version_route.backend_server.to_socket_addrs() {
+                problems.push(format!(
+                    "{}: version_routes[{}].backend_server {:?} cannot be resolved: {}",
+                    at, route, version_route.backend_server, e
+                ));
+            }
+        }
+        if let Some(path) = server.messages.as_ref().and_then(|m| m.favicon.as_ref()) {
+            if let Err(e) = favicon::load(path) {
+                problems.push(format!("{}: messages.favicon: {}", at, e));
+            }
+        }
+    }
+
+    if config.auto_start {
+        let uses_global_panel = |s: &ServerEntry| match &s.power {
+            None => true,
+            Some(PowerConfig::Pterodactyl(credentials)) => credentials.panel_link.is_none(),
+            Some(_) => false,
+        };
+        let panel_link = config.panel_link.trim();
+        let placeholder = panel_link.is_empty() || panel_link == PLACEHOLDER_PANEL_LINK;
+        if placeholder && servers.iter().any(uses_global_panel) {
+            problems.push(format!(
+                "config: panel_link: auto_start is enabled but panel_link is {:?}",
+                config.panel_link
+            ));
+        }
+        // Servers on the global panel would only repeat the problem above
+        let checked = servers.iter().filter(|s| !placeholder || !uses_global_panel(s));
+        problems.extend(
+            validate_controllers(config, checked)
+                .into_iter()
+                .map(|problem| format!("auto_start: {}", problem)),
+        );
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reports_server_credentials_alongside_a_placeholder_panel() {
+        let config = Config {
+            auto_start: true,
+            ..Config::default()
+        };
+        let servers = [
+            ServerEntry::for_test("global", json!(null)),
+            ServerEntry::for_test("own", json!({"type": "pterodactyl", "panel_link": "https://panel.example"})),
+            ServerEntry::for_test("hook", json!({"type": "webhook", "url": "https://hooks.example"})),
+        ];
+        let problems = validate(&config, &servers, "servers.json");
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].starts_with("config: panel_link:"), "{:?}", problems);
+        assert!(problems[1].starts_with("auto_start: server own:") && problems[1].contains("API key"), "{:?}", problems);
+    }
+
+    #[test]
+    fn checks_every_server_once_the_panel_is_set() {
+        let config = Config {
+            auto_start: true,
+            panel_link: "https://panel.example".to_string(),
+            ..Config::default()
+        };
+        let servers = [
+            ServerEntry::for_test("global", json!(null)),
+            ServerEntry::for_test("own", json!({"type": "pterodactyl"})),
+        ];
+        let problems = validate(&config, &servers, "servers.json");
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].starts_with("auto_start: server global:"), "{:?}", problems);
+        assert!(problems[1].starts_with("auto_start: server own:"), "{:?}", problems);
+    }
+}
@@ -4,50 +4,140 @@ extern crate log;
 extern crate anyhow;
 
 pub mod utils {
+    pub mod chat;
+    pub mod codec;
+    pub mod favicon;
     pub mod packet;
+    pub mod protocol;
+    pub mod relay;
+    pub mod unix_http;
 }
 pub mod core {
+    pub mod cli;
     pub mod config;
+    pub mod controller;
+    pub mod docker;
+    pub mod format;
+    pub mod power;
     pub mod proxy;
+    pub mod rules;
+    pub mod secret;
     pub mod servers;
+    pub mod tcpshield;
+    pub mod validate;
 }
 
 use anyhow::Result;
-use crate::core::config::Config;
-use crate::utils::packet::{HandshakeRequest, NextState};
-use crate::core::servers::Servers;
+use clap::Parser;
+use crate::core::cli::{Cli, Command};
+use crate::core::docker;
+use crate::core::format;
+use crate::core::config::{Config, HoldLogin};
+use crate::utils::{chat, protocol, relay};
+use crate::utils::codec::{self, Packet};
+use crate::utils::packet::{
+    HandshakeRequest, LoginDisconnect, LoginStart, NextState, PacketReader, PacketWriter, StatusResponse,
+};
+use crate::core::servers::{ServerEntry, Servers, ServersFile};
+use crate::core::controller::{controller_for, PowerController, PowerSignal};
+use crate::core::validate::validate;
+use crate::core::secret;
+use crate::core::power::{PowerState, PowerTracker, StartDecision};
 use crate::core::proxy::ProxyProtocol;
+use crate::core::rules::{self, Action};
+use crate::core::tcpshield::{self, TcpShield};
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::net::SocketAddr;
 use std::path::Path;
-use std::net::ToSocketAddrs;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::AsyncWriteExt;
-use reqwest::Client;
-use std::io::Cursor;
-use serde_json::json;
-use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use tokio::time::Instant;
 use crate::utils::packet;
 
+const SERVERS_DIR_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a server's queried power state is reused before asking again
+const POWER_STATE_MAX_AGE: Duration = Duration::from_secs(10);
+/// How long the idle monitor waits for a backend to accept a connection
+const BACKEND_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
 #[tokio::main]
 async fn main() {
-    if let Err(e) = launch_sequence() {
-        eprintln!("Error: {}", e);
-        std::process::exit(1);
-    }
+    let cli = Cli::parse();
 
     if env::var("RUST_LOG").is_err() {
         env::set_var("RUST_LOG", "info");
     }
     env_logger::init();
 
-    let config = load_conf();
-    let servers = Servers::load().expect("Failed to load servers.json");
+    if let Some(Command::Convert { input, output }) = &cli.command {
+        if let Err(e) = convert(input, output) {
+            exit_with_problems(&[e.to_string()]);
+        }
+        info!("Converted {} to {}", input.display(), output.display());
+        return;
+    }
+
+    if cli.print_config {
+        let config = load_conf(&cli).unwrap_or_else(|e| exit_with_problems(&[e.to_string()]));
+        print!("{}", serde_yaml::to_string(&config.redacted()).expect("Failed to serialize configuration"));
+        return;
+    }
+
+    if let Err(e) = launch_sequence() {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+
+    // Collect every problem before giving up, so they can be fixed in one go
+    let mut problems = Vec::new();
+    let config = load_conf(&cli).map_err(|e| problems.push(e.to_string())).ok();
+    let servers = config
+        .as_ref()
+        .and_then(|config| Servers::load(config).map_err(|e| problems.push(e.to_string())).ok());
+    let (config, servers) = match (config, servers) {
+        (Some(config), Some(servers)) => (config, servers),
+        _ => exit_with_problems(&problems),
+    };
     debug!("Configuration: {:?}", config);
+    if servers.list_servers().iter().any(|s| s.has_inline_api_key()) {
+        secret::warn_if_world_readable(servers.path().unwrap_or(&cli.config));
+    }
+
+    let source = servers.path().unwrap_or(&cli.config).display().to_string();
+    let problems = validate(&config, &servers.list_servers(), &source);
+    if !problems.is_empty() {
+        exit_with_problems(&problems);
+    }
 
     start(config, servers).await;
 }
 
+/// Rewrites a config or server list in the format of `output`'s extension.
+/// A file with nothing but a `servers` key is treated as a server list.
+fn convert(input: &Path, output: &Path) -> Result<()> {
+    let raw: serde_json::Value = format::read_file(input)?;
+    let is_server_list = raw.as_object().is_some_and(|o| o.len() == 1 && o.contains_key("servers"));
+    if is_server_list {
+        let servers: ServersFile = format::read_file(input)?;
+        format::write_file(output, &servers)
+    } else {
+        let config: Config = format::read_file(input)?;
+        format::write_file(output, &config)
+    }
+}
+
+fn exit_with_problems(problems: &[String]) -> ! {
+    for problem in problems {
+        error!("{}", problem);
+    }
+    error!("Found {} configuration problem(s), exiting", problems.len());
+    std::process::exit(1);
+}
+
 fn launch_sequence() -> Result<(), Box<dyn std::error::Error>> {
     const LAUNCH_ASCII: &str = r#"
  __  __           _ ____                      
@@ -66,8 +156,35 @@ fn launch_sequence() -> Result<(), Box<dyn std::error::Error>> {
 async fn start(config: Config, servers: Servers) {
     let listen_addr = config.get_listen_addr();
     info!("Listening on {}", listen_addr);
-    let mut listener = TcpListener::bind(listen_addr).await.unwrap();
-    
+    let mut listener = match TcpListener::bind(&listen_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen on {}: {}", listen_addr, e);
+            std::process::exit(1);
+        }
+    };
+    let power = PowerTracker::new();
+    let tcpshield = match config.tcpshield.enabled.then(|| TcpShield::new(&config.tcpshield)) {
+        Some(Ok(tcpshield)) => Some(Arc::new(tcpshield)),
+        Some(Err(e)) => {
+            error!("Failed to set up TCPShield: {}", e);
+            std::process::exit(1);
+        }
+        None => None,
+    };
+    if config.splice && !relay::splice_available() {
+        warn!("splice is enabled but not available here, connections will be copied instead");
+    }
+
+    tokio::spawn(servers.clone().watch(config.clone(), SERVERS_DIR_POLL_INTERVAL));
+    if config.docker.enabled {
+        info!("Discovering servers from Docker at {}", config.docker.socket.display());
+        tokio::spawn(docker::run(config.docker.clone(), servers.clone()));
+    }
+
+    // Always running, as servers with `auto_stop` may be added later
+    tokio::spawn(idle_monitor(config.clone(), servers.clone(), power.clone()));
+
     loop {
         let client = accept_client(&mut listener).await;
         if let Err(e) = client {
@@ -78,8 +195,10 @@ async fn start(config: Config, servers: Servers) {
         debug!("Client connected from {:?}", addr);
         let config = config.clone();
         let servers = servers.clone();
+        let power = power.clone();
+        let tcpshield = tcpshield.clone();
         tokio::spawn(async move {
-            let result = handle_client(&config, &servers, stream, addr).await;
+            let result = handle_client(&config, &servers, &power, tcpshield.as_deref(), stream, addr).await;
             if let Err(e) = result {
                 error!("{}: An error occurred: {}", addr, e);
             }
@@ -96,32 +215,124 @@ async fn accept_client(listener: &mut TcpListener) -> Result<(TcpStream, SocketA
 async fn handle_client(
     config: &Config, 
     servers: &Servers, 
+    power: &PowerTracker,
+    tcpshield: Option<&TcpShield>,
     mut stream: TcpStream, 
     addr: SocketAddr
 ) -> Result<()> {
-    let handshake = HandshakeRequest::read(&mut stream).await?;
-    let host: &str = &handle_hostname(handshake.get_host()).await;
+    let mut handshake = HandshakeRequest::read(&mut stream).await?;
+    let mut host = handshake.get_hostname().to_string();
+    // From here on `addr` is the real client, as vouched for by the edge
+    let addr = match tcpshield {
+        Some(tcpshield) if tcpshield::has_envelope(&host) => match tcpshield.verify(handshake.get_host(), addr.ip()) {
+            Ok(envelope) => {
+                debug!("{}: TCPShield client is {}", addr, envelope.client);
+                host = envelope.hostname;
+                envelope.client
+            }
+            Err(e) => {
+                warn!("{}: Rejected TCPShield envelope: {}", addr, e);
+                return Ok(());
+            }
+        },
+        _ => {
+            host = tcpshield::strip_envelope(&host).to_string();
+            addr
+        }
+    };
+    // Pass the handshake on without the envelope, the PROXY header carries the client
+    if host != handshake.get_hostname() {
+        handshake = handshake.with_hostname(&host);
+    }
+    let login_start = match handshake.get_next_state() {
+        NextState::Login => Some(LoginStart::read(&mut stream).await?),
+        NextState::Status => None,
+    };
+    let context = chat::Context::new(handshake.get_version())
+        .with("hostname", &host)
+        .with("client_ip", addr.ip())
+        .with("username", login_start.as_ref().map(LoginStart::get_username).unwrap_or_default())
+        .with("protocol_version", handshake.get_version())
+        .with("client_version", client_version(handshake.get_version()))
+        .with("supported_versions", "")
+        .with("server_id", "")
+        .with("players_online", 0)
+        .with("max_players", 0)
+        .with("server_state", "")
+        .with("start_eta", 0)
+        .with("elapsed", 0);
+
+    let connection = rules::Connection {
+        hostname: &host,
+        port: handshake.get_port(),
+        protocol: handshake.get_version(),
+        ip: addr.ip(),
+        username: login_start.as_ref().map(LoginStart::get_username),
+        forge: handshake.get_forge().is_some(),
+        next_state: handshake.get_next_state(),
+    };
+    if let Some((index, rule)) = rules::first_match(&config.rules, &connection) {
+        info!("{}: {}: {}:{} matched {}", addr, handshake.get_next_state(), host, handshake.get_port(), rule.label(index));
+        match &rule.action {
+            Action::Kick(message) => {
+                let reason = config.get_rule_kick_msg(message, &context);
+                send(&mut stream, &LoginDisconnect { reason }).await?;
+                return Ok(());
+            }
+            Action::Motd(motd) => {
+                let json = config.get_rule_motd(motd, &context);
+                send(&mut stream, &StatusResponse { json }).await?;
+                return Ok(());
+            }
+            Action::Route(target) => {
+                let mut server = match TcpStream::connect(target.backend_server.as_str()).await {
+                    Ok(server) => server,
+                    Err(e) => {
+                        warn!("{}: failed to connect to routed backend {}: {}", addr, target.backend_server, e);
+                        match handshake.get_next_state() {
+                            NextState::Login => {
+                                let reason = config.get_offline_route_kick_msg(&context);
+                                send(&mut stream, &LoginDisconnect { reason }).await?;
+                            }
+                            NextState::Status => {
+                                let json = config.get_offline_route_motd(&context);
+                                send(&mut stream, &StatusResponse { json }).await?;
+                            }
+                        }
+                        return Ok(());
+                    }
+                };
+                server.set_nodelay(true)?;
+                forward(&mut server, addr, &handshake, login_start.as_ref(), target.proxy_protocol).await?;
+                bridge(stream, server, addr, config.splice, || {}).await;
+                return Ok(());
+            }
+            Action::Redirect(hostname) => host = hostname.clone(),
+        }
+    }
+    let host = host.as_str();
     let server_entry = servers.get_by_hostname(host);
 
     info!(
-        "{}: {}: {}:{} -> {}",
+        "{}: {}: {}:{}{} -> {}",
         addr,
         handshake.get_next_state(),
         host,
         handshake.get_port(),
+        handshake.get_forge().map(|marker| format!(" [{}]", marker)).unwrap_or_default(),
         server_entry
             .as_ref()
-            .map(|s| s.backend_server.to_string())
+            .map(|s| s.backend_for(handshake.get_version()).to_string())
             .unwrap_or_else(|| "unknown".to_string())
     );
 
     if server_entry.is_none() {
         if *handshake.get_next_state() == NextState::Login {
-            let mut kick_msg = config.get_unknown_host_kick_msg();
-            write_string(&mut stream, &mut &**&mut kick_msg).await?;
+            let reason = config.get_unknown_host_kick_msg(&context);
+            send(&mut stream, &LoginDisconnect { reason }).await?;
         } else if *handshake.get_next_state() == NextState::Status {
-            let mut motd = config.get_unknown_host_motd();
-            write_string(&mut stream, &mut &**&mut motd).await?;
+            let json = config.get_unknown_host_motd(&context);
+            send(&mut stream, &StatusResponse { json }).await?;
         }
         return Ok(());
     }
@@ -129,134 +340,397 @@ async fn handle_client(
     let server_entry = server_entry.unwrap();
     let server_id = &server_entry.id;
 
-    // Try to connect to the target server
-    let server_addr = server_entry.backend_server.to_socket_addrs()?.next().unwrap();
-    let server_result = TcpStream::connect(&server_addr).await;
-
-    if let Err(e) = server_result {
-        warn!("Failed to connect to backend server: {}", e);
-        if *handshake.get_next_state() == NextState::Login {
-            if config.auto_start {
-                // Try to start the server
-                if let Err(e) = start_server(config, server_id).await {
-                    error!("Failed to start server: {}", e);
-                } else {
-                    info!("Server start signal sent for {}", server_id);
-                }
-                let mut kick_msg = config.get_offline_server_starting_msg();
-                write_string(&mut stream, &mut &**&mut kick_msg).await?;
-            } else {
-                let mut kick_msg = config.get_offline_server_kick_msg();
-                write_string(&mut stream, &mut &**&mut kick_msg).await?;
+    if server_entry.maintenance {
+        let context = server_context(&context, config, power, &server_entry);
+        match handshake.get_next_state() {
+            NextState::Login => {
+                let reason = config.get_maintenance_msg(&server_entry, &context);
+                send(&mut stream, &LoginDisconnect { reason }).await?;
+            }
+            NextState::Status => {
+                let json = config.get_maintenance_motd(&server_entry, &context);
+                send(&mut stream, &StatusResponse { json }).await?;
             }
-        } else if *handshake.get_next_state() == NextState::Status {
-            let mut motd = config.get_offline_server_motd_not_starting(server_id).await;
-            write_string(&mut stream, &mut &**&mut motd).await?;
         }
         return Ok(());
     }
 
-    let mut server = server_result.unwrap();
+    let supported = server_entry.versions.as_ref().is_none_or(|v| v.contains(handshake.get_version()));
+    if !supported && *handshake.get_next_state() == NextState::Login {
+        info!("{}: protocol {} is not supported by {}", addr, handshake.get_version(), server_id);
+        let context = server_context(&context, config, power, &server_entry);
+        let reason = config.get_unsupported_version_msg(&server_entry, &context);
+        send(&mut stream, &LoginDisconnect { reason }).await?;
+        return Ok(());
+    }
+
+    // Try to connect to the target server
+    let backend = server_entry.backend_for(handshake.get_version());
+    let mut server = match TcpStream::connect(backend).await {
+        Ok(server) => server,
+        Err(e) => {
+            warn!("Failed to connect to backend server: {}", e);
+            let next_state = handshake.get_next_state();
+            match handle_offline_server(config, servers, power, &context, next_state, &mut stream, &server_entry).await? {
+                Some(server) => server,
+                None => return Ok(()),
+            }
+        }
+    };
     server.set_nodelay(true)?;
+    power.mark_running(server_id);
 
     // Only increment player count if this is a login attempt AND we successfully connected
     if *handshake.get_next_state() == NextState::Login {
         servers.increment_player_count(host);
     }
 
-    // Send PROXY protocol header
-    let proxy = ProxyProtocol::new(addr, server_addr);
-    let header = proxy.generate_header();
-    server.write_all(&header).await?;
+    let proxy_protocol = server_entry.proxy_protocol_for(handshake.get_version());
+    forward(&mut server, addr, &handshake, login_start.as_ref(), proxy_protocol).await?;
 
-    // Send Minecraft handshake
-    packet::write_var_int(&mut server, handshake.get_size()).await?;
-    server.write_all(handshake.get_raw_body()).await?;
+    if *handshake.get_next_state() == NextState::Status && config.online_motd.is_some() {
+        let context = server_context(&context, config, power, &server_entry);
+        override_status(config, &context, &mut stream, &mut server).await?;
+    }
 
-    let (mut client_reader, mut client_writer) = tokio::io::split(stream);
-    let (mut server_reader, mut server_writer) = tokio::io::split(server);
-    
     let host = host.to_string();
-    let servers_clone = servers.clone();
+    let servers = servers.clone();
     let next_state = handshake.get_next_state().clone();
-    
-    tokio::spawn(async move {
-        let result = tokio::io::copy(&mut client_reader, &mut server_writer).await;
-        if let Some(err) = result.err() {
+    bridge(stream, server, addr, config.splice, move || {
+        // Only decrement if this was a login connection
+        if next_state == NextState::Login {
+            servers.decrement_player_count(&host);
+        }
+    })
+    .await;
+    Ok(())
+}
+
+/// Sends the PROXY protocol header if asked to, then replays the handshake and
+/// the Login Start read from the client, all in one write.
+async fn forward(
+    server: &mut TcpStream,
+    addr: SocketAddr,
+    handshake: &HandshakeRequest,
+    login_start: Option<&LoginStart>,
+    proxy_protocol: bool,
+) -> Result<()> {
+    let mut out = Vec::with_capacity(handshake.get_raw_body().len() + 128);
+    if proxy_protocol {
+        let proxy = ProxyProtocol::new(addr, server.peer_addr()?);
+        out.extend_from_slice(&proxy.generate_header());
+    }
+
+    codec::put_frame(&mut out, handshake.get_raw_body());
+
+    if let Some(login_start) = login_start {
+        out.extend_from_slice(login_start.get_frame());
+    }
+    server.write_all(&out).await?;
+    Ok(())
+}
+
+/// Relays bytes both ways until both sides are done, with splice(2) if
+/// `splice` is set. `on_client_closed` runs once the client stops sending.
+async fn bridge(
+    stream: TcpStream,
+    server: TcpStream,
+    addr: SocketAddr,
+    splice: bool,
+    on_client_closed: impl FnOnce() + Send + 'static,
+) {
+    let (client_reader, client_writer) = stream.into_split();
+    let (server_reader, server_writer) = server.into_split();
+
+    let upstream = tokio::spawn(async move {
+        let (sent, result) = relay::relay(client_reader, server_writer, splice).await;
+        if let Err(err) = result {
             debug!(
                 "{}: An error occurred in client-to-server bridge. Maybe disconnected: {}",
                 addr, err
             );
         }
-        // Only decrement if this was a login connection
-        if next_state == NextState::Login {
-            servers_clone.decrement_player_count(&host);
-        }
+        on_client_closed();
+        sent
     });
-    
-    let result = tokio::io::copy(&mut server_reader, &mut client_writer).await;
-    if let Some(err) = result.err() {
+
+    let (received, result) = relay::relay(server_reader, client_writer, splice).await;
+    if let Err(err) = result {
         debug!(
             "{}: An error occurred in server-to-client bridge. Maybe disconnected: {}",
             addr, err
         );
     }
-    Ok(())
+    let sent = upstream.await.unwrap_or_default();
+    debug!("{}: Closed after {} bytes to the server and {} bytes back", addr, sent, received);
 }
 
-async fn handle_hostname(hostname: &str) -> String {
-    let mut host: String = hostname.to_owned();
+/// Answers a client whose backend refused the connection. Returns the backend
+/// stream if the connection was held until the backend came up, or `None`
+/// once the client has been answered.
+async fn handle_offline_server(
+    config: &Config,
+    servers: &Servers,
+    power: &PowerTracker,
+    context: &chat::Context,
+    next_state: &NextState,
+    stream: &mut TcpStream,
+    server: &ServerEntry,
+) -> Result<Option<TcpStream>> {
+    let server_id = &server.id;
+    power.mark_unreachable(server_id, config.start_timeout());
+    let protocol = context.protocol();
+    // Placeholders follow the power state, so refresh them before each message
+    let context = || server_context(context, config, power, server);
+    if *next_state == NextState::Login {
+        if config.auto_start {
+            let controller = controller_for(config, server)?;
+            if power.claim_query(server_id, POWER_STATE_MAX_AGE, config.start_cooldown()) {
+                refresh_power_state(power, server_id, &*controller).await;
+            }
+            if let Some(reason) = power_state_kick_msg(config, power.state(server_id), &context()) {
+                send(stream, &LoginDisconnect { reason }).await?;
+                return Ok(None);
+            }
+            match power.begin_start(server_id, config.start_cooldown()) {
+                StartDecision::Send => {
+                    if let Err(e) = controller.send(PowerSignal::Start).await {
+                        power.record_error(server_id, "start", &e);
+                        power.start_failed(server_id);
+                    } else {
+                        info!("Server start signal sent for {}", server_id);
+                        refresh_power_state(power, server_id, &*controller).await;
+                    }
+                }
+                StartDecision::InFlight => debug!("Start of {} already in flight", server_id),
+                StartDecision::CoolingDown(remaining) => {
+                    debug!("Start of {} is cooling down for another {:?}", server_id, remaining)
+                }
+            }
+            if let Some(reason) = power_state_kick_msg(config, power.state(server_id), &context()) {
+                send(stream, &LoginDisconnect { reason }).await?;
+                return Ok(None);
+            }
+            if config.hold_login().enabled && power.state(server_id) == PowerState::Starting {
+                let held = connect_with_retry(servers, server, protocol, config.hold_login()).await;
+                if let Some(server) = held {
+                    info!("Backend {} came up, replaying login for held client", server_id);
+                    return Ok(Some(server));
+                }
+                warn!("Backend {} did not come up in time for held client", server_id);
+            }
+            let reason = config.get_offline_server_starting_msg(server, &context());
+            send(stream, &LoginDisconnect { reason }).await?;
+        } else {
+            let reason = config.get_offline_server_kick_msg(server, &context());
+            send(stream, &LoginDisconnect { reason }).await?;
+        }
+    } else if *next_state == NextState::Status {
+        let context = context();
+        let json = match power.state(server_id) {
+            PowerState::Starting => config.get_offline_server_motd_starting(server, &context),
+            PowerState::Suspended => config.get_suspended_motd(server, &context),
+            PowerState::Installing => config.get_installing_motd(server, &context),
+            _ => config.get_offline_server_motd_not_starting(server, &context),
+        };
+        send(stream, &StatusResponse { json }).await?;
+    }
+    Ok(None)
+}
 
-    // TCPShield Support (UNTESTED!)
-    if host.contains("///") {
-        let parts = host.split("///");
-        for part in parts {
-            host = part.to_owned();
-            break;
+/// Pulls the controller's view of a backend we just failed to connect to.
+async fn refresh_power_state(power: &PowerTracker, server_id: &str, controller: &dyn PowerController) {
+    match controller.query().await {
+        Ok(Some(state)) => {
+            let state = match state {
+                // The process is up but not accepting connections yet
+                PowerState::Running => PowerState::Starting,
+                // Panels report offline for a moment after a start request
+                PowerState::Idle if power.state(server_id) == PowerState::Starting => return,
+                state => state,
+            };
+            debug!("Server {} reported as {}", server_id, state);
+            power.set_state(server_id, state);
         }
+        Ok(None) => {}
+        Err(e) => power.record_error(server_id, "query the state of", &e),
     }
+}
 
-    // Forge Support
-    if host.contains("FML2") {
-        host = host.replace("FML2", "");
-    } else if host.contains("FML") {
-        host = host.replace("FML", "");
+/// Relays the status request and answers with the backend's status, its MOTD
+/// replaced by `online_motd`. The ping that follows is bridged as usual.
+async fn override_status(
+    config: &Config,
+    context: &chat::Context,
+    client: &mut TcpStream,
+    server: &mut TcpStream,
+) -> Result<()> {
+    let request = packet::read_frame(client).await?;
+    server.write_all(&request).await?;
+    let mut reader = PacketReader::new(&mut *server);
+    let response = reader.read_packet().await?;
+    let status = StatusResponse::from_bytes(response)?;
+    match config.get_online_status(&status.json, context) {
+        Some(json) => send(client, &StatusResponse { json }).await,
+        None => PacketWriter::new(client).write_packet(response).await,
     }
-    host
 }
 
-async fn write_string(stream: &mut TcpStream, string: &mut &str) -> Result<()> {
-    let mut temp: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-    crate::utils::packet::write_var_int(&mut temp, 0).await?;
-    crate::utils::packet::write_var_int(&mut temp, string.len() as i32).await?;
-    temp.write_all(&string.as_bytes()).await?;
-    let temp = temp.into_inner();
-    crate::utils::packet::write_var_int(stream, temp.len() as i32).await?;
-    stream.write_all(&temp).await?;
-    Ok(())
+/// Release name for the client's protocol, or the raw number for unknown ones.
+fn client_version(protocol: i32) -> String {
+    protocol::version_name(protocol).unwrap_or_else(|| format!("protocol {}", protocol))
 }
 
-fn load_conf() -> Config {
-    let config_path = Path::new("./config.yml");
+/// Adds the placeholders describing `server` and its power state to `context`.
+fn server_context(context: &chat::Context, config: &Config, power: &PowerTracker, server: &ServerEntry) -> chat::Context {
+    let id = &server.id;
+    context
+        .clone()
+        .with("server_id", id)
+        .with("players_online", server.player_count.load(Ordering::SeqCst))
+        .with("max_players", config.max_players(server))
+        .with("supported_versions", server.versions.as_ref().map(ToString::to_string).unwrap_or_default())
+        .with("server_state", power.state(id))
+        .with("start_eta", power.start_eta(id, config.start_timeout()).unwrap_or_default().as_secs())
+        .with("elapsed", power.elapsed(id).unwrap_or_default().as_secs())
+}
+
+fn power_state_kick_msg(config: &Config, state: PowerState, context: &chat::Context) -> Option<String> {
+    match state {
+        PowerState::Suspended => Some(config.get_suspended_msg(context)),
+        PowerState::Installing => Some(config.get_installing_msg(context)),
+        _ => None,
+    }
+}
+
+/// Keeps dialing the backend with exponential backoff until it accepts or the
+/// hold timeout runs out. The address is looked up again on every attempt, as
+/// a starting backend may change it.
+async fn connect_with_retry(
+    servers: &Servers,
+    server: &ServerEntry,
+    protocol: i32,
+    hold: &HoldLogin,
+) -> Option<TcpStream> {
+    let deadline = Instant::now() + Duration::from_secs(hold.timeout_secs);
+    let mut backoff = Duration::from_millis(hold.initial_backoff_ms);
+    let max_backoff = Duration::from_millis(hold.max_backoff_ms);
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        tokio::time::sleep(backoff.min(deadline - now)).await;
+        let backend = servers
+            .get_by_id(&server.id)
+            .map(|s| s.backend_for(protocol).to_string())
+            .unwrap_or_else(|| server.backend_for(protocol).to_string());
+        match TcpStream::connect(backend.as_str()).await {
+            Ok(server) => return Some(server),
+            Err(e) => debug!("Backend {} still unreachable: {}", backend, e),
+        }
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
+
+async fn send(stream: &mut TcpStream, packet: &impl Packet) -> Result<()> {
+    PacketWriter::new(stream).send(packet).await
+}
+
+/// Loads the configuration with environment and command line overrides on
+/// top of the file: defaults < file < env < CLI.
+fn load_conf(cli: &Cli) -> Result<Config> {
+    let config_path = cli.config.as_path();
     info!("Configuration file: {:?}", config_path);
-    Config::load_or_init(config_path)
+    let mut overrides = Config::env_overrides(env::vars());
+    overrides.extend(cli.overrides());
+    let config = Config::load_layered(config_path, &overrides)?;
+    let key_from_file = !overrides.iter().any(|(key, _)| key == "api_key");
+    if !config.api_key.is_empty() && key_from_file {
+        secret::warn_if_world_readable(config_path);
+    }
+    Ok(config)
 }
 
-async fn start_server(config: &Config, server_id: &str) -> Result<()> {
-    let client = Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert("Authorization", HeaderValue::from_str(&format!("Bearer {}", config.api_key()))?);
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-
-    let url = format!("{}/api/client/servers/{}/power", config.panel_link(), server_id);
-    
-    client.post(url)
-        .headers(headers)
-        .json(&json!({
-            "signal": "start"
-        }))
-        .send()
-        .await?;
+async fn send_power_signal(config: &Config, server: &ServerEntry, signal: PowerSignal) -> Result<()> {
+    controller_for(config, server)?.send(signal).await
+}
 
-    Ok(())
+/// Whether any of the server's backends accepts a connection.
+async fn backend_reachable(server: &ServerEntry) -> bool {
+    for backend in server.backends() {
+        if let Ok(Ok(_)) = tokio::time::timeout(BACKEND_PROBE_TIMEOUT, TcpStream::connect(backend)).await {
+            return true;
+        }
+    }
+    false
+}
+
+/// Stops backends that have had no players for their idle timeout, and kills
+/// them if they ignore the stop for longer than the grace period. Servers are
+/// probed once when first seen, so those that were already up are stopped too.
+async fn idle_monitor(config: Config, servers: Servers, power: PowerTracker) {
+    let settings = config.idle_stop().clone();
+    let mut interval = tokio::time::interval(Duration::from_secs(settings.check_interval_secs.max(1)));
+    let mut empty_since: HashMap<String, Instant> = HashMap::new();
+    let mut killed: HashSet<String> = HashSet::new();
+    let mut probed: HashSet<String> = HashSet::new();
+
+    loop {
+        interval.tick().await;
+        for server in servers.list_servers() {
+            if !server.auto_stop.unwrap_or(settings.enabled) {
+                continue;
+            }
+            let id = &server.id;
+            if power.state(id) == PowerState::Idle && probed.insert(id.clone()) && backend_reachable(&server).await {
+                info!("Server {} is already up, stopping it once idle", id);
+                power.mark_running(id);
+            }
+            let elapsed = power.elapsed(id).unwrap_or_default();
+            match power.state(id) {
+                PowerState::Running => {
+                    killed.remove(id);
+                    if server.player_count.load(Ordering::SeqCst) > 0 {
+                        empty_since.remove(id);
+                        continue;
+                    }
+                    let empty_for = empty_since.entry(id.clone()).or_insert_with(Instant::now).elapsed();
+                    let timeout = Duration::from_secs(server.idle_timeout_secs.unwrap_or(settings.timeout_secs));
+                    let min_uptime = Duration::from_secs(server.min_uptime_secs.unwrap_or(settings.min_uptime_secs));
+                    if empty_for < timeout || elapsed < min_uptime {
+                        continue;
+                    }
+                    info!("Server {} has been empty for {:?}, stopping it", id, empty_for);
+                    match send_power_signal(&config, &server, PowerSignal::Stop).await {
+                        Ok(()) => {
+                            power.mark_stopping(id);
+                            empty_since.remove(id);
+                        }
+                        Err(e) => power.record_error(id, "stop", &e),
+                    }
+                }
+                PowerState::Stopping => {
+                    let Some(kill_after) = settings.kill_after_secs else { continue };
+                    if killed.contains(id) || elapsed < Duration::from_secs(kill_after) {
+                        continue;
+                    }
+                    if !backend_reachable(&server).await {
+                        power.mark_unreachable(id, config.start_timeout());
+                        continue;
+                    }
+                    warn!("Server {} ignored the stop signal for {:?}, killing it", id, elapsed);
+                    match send_power_signal(&config, &server, PowerSignal::Kill).await {
+                        Ok(()) => {
+                            killed.insert(id.clone());
+                        }
+                        Err(e) => power.record_error(id, "kill", &e),
+                    }
+                }
+                _ => {
+                    empty_since.remove(id);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,507 @@
+use serde::Serialize;
+use serde_json::Value;
+
+/// First protocol version (1.16, 20w17a) whose clients understand hex colors
+pub const HEX_COLOR_PROTOCOL: i32 = 735;
+
+/// Legacy format codes and their chat names, RGB for colors.
+const COLORS: [(char, &str, u32); 16] = [
+    ('0', "black", 0x000000),
+    ('1', "dark_blue", 0x0000AA),
+    ('2', "dark_green", 0x00AA00),
+    ('3', "dark_aqua", 0x00AAAA),
+    ('4', "dark_red", 0xAA0000),
+    ('5', "dark_purple", 0xAA00AA),
+    ('6', "gold", 0xFFAA00),
+    ('7', "gray", 0xAAAAAA),
+    ('8', "dark_gray", 0x555555),
+    ('9', "blue", 0x5555FF),
+    ('a', "green", 0x55FF55),
+    ('b', "aqua", 0x55FFFF),
+    ('c', "red", 0xFF5555),
+    ('d', "light_purple", 0xFF55FF),
+    ('e', "yellow", 0xFFFF55),
+    ('f', "white", 0xFFFFFF),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Style {
+    color: Option<String>,
+    bold: bool,
+    italic: bool,
+    underlined: bool,
+    strikethrough: bool,
+    obfuscated: bool,
+}
+
+impl Style {
+    fn set_decoration(&mut self, name: &str, value: bool) -> bool {
+        match name {
+            "bold" | "b" => self.bold = value,
+            "italic" | "i" | "em" => self.italic = value,
+            "underlined" | "u" => self.underlined = value,
+            "strikethrough" | "st" => self.strikethrough = value,
+            "obfuscated" | "obf" => self.obfuscated = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// One run of text sharing a style, serialized as a chat component.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Component {
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bold: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub italic: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub underlined: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strikethrough: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub obfuscated: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extra: Vec<Component>,
+}
+
+impl Component {
+    fn styled(text: String, style: &Style) -> Self {
+        let flag = |set: bool| if set { Some(true) } else { None };
+        Self {
+            text,
+            color: style.color.clone(),
+            bold: flag(style.bold),
+            italic: flag(style.italic),
+            underlined: flag(style.underlined),
+            strikethrough: flag(style.strikethrough),
+            obfuscated: flag(style.obfuscated),
+            extra: Vec::new(),
+        }
+    }
+}
+
+/// What a message is rendered for: the client's protocol version and the
+/// values of `{placeholder}`s.
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    protocol: i32,
+    placeholders: Vec<(&'static str, String)>,
+}
+
+impl Context {
+    pub fn new(protocol: i32) -> Self {
+        Self { protocol, placeholders: Vec::new() }
+    }
+
+    /// Sets the value substituted for `{name}`.
+    pub fn with(mut self, name: &'static str, value: impl ToString) -> Self {
+        let value = value.to_string();
+        match self.placeholders.iter_mut().find(|(n, _)| *n == name) {
+            Some(placeholder) => placeholder.1 = value,
+            None => self.placeholders.push((name, value)),
+        }
+        self
+    }
+
+    pub fn protocol(&self) -> i32 {
+        self.protocol
+    }
+
+    /// Substitutes every known placeholder in `text`. Unknown ones are kept.
+    /// Values are inserted in one pass, so placeholders inside them, such as a
+    /// player named `{server_id}`, are left alone.
+    pub fn fill(&self, text: &str) -> String {
+        let mut filled = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find('{') {
+            filled.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let placeholder = after.find('}').and_then(|end| {
+                let name = &after[..end];
+                self.placeholders.iter().find(|(n, _)| *n == name).map(|(_, value)| (value, end))
+            });
+            match placeholder {
+                Some((value, end)) => {
+                    filled.push_str(value);
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    filled.push('{');
+                    rest = after;
+                }
+            }
+        }
+        filled.push_str(rest);
+        filled
+    }
+}
+
+/// Turns a configured message into a chat component for the client in
+/// `context`. Accepts raw JSON components, legacy `§`/`&` codes (including
+/// `&#rrggbb` hex colors) and MiniMessage-style tags such as `<red>`,
+/// `<#ff8800>` and `<bold>`. Hex colors are replaced by the nearest legacy
+/// color for clients older than 1.16. Placeholders are filled in after
+/// parsing, so their values are never read as formatting and stay valid JSON.
+pub fn component(input: &str, context: &Context) -> Value {
+    let mut value = parse_json(input).unwrap_or_else(|| {
+        let extra = parse(input);
+        serde_json::to_value(Component { extra, ..Component::default() }).unwrap_or(Value::Null)
+    });
+    fill(&mut value, context);
+    if context.protocol < HEX_COLOR_PROTOCOL {
+        downsample(&mut value);
+    }
+    value
+}
+
+/// Renders a configured message as a `§`-coded string, for fields such as the
+/// status `version.name` that do not take components.
+pub fn legacy(input: &str, context: &Context) -> String {
+    let mut output = String::new();
+    let mut current = Style::default();
+    for part in parse(input) {
+        let style = Style {
+            color: part.color.clone(),
+            bold: part.bold.unwrap_or(false),
+            italic: part.italic.unwrap_or(false),
+            underlined: part.underlined.unwrap_or(false),
+            strikethrough: part.strikethrough.unwrap_or(false),
+            obfuscated: part.obfuscated.unwrap_or(false),
+        };
+        if style != current {
+            // Legacy colors reset decorations, so always emit the color first
+            match style.color.as_deref().map(nearest_named) {
+                Some(code) => output.push_str(&format!("§{}", code)),
+                None => output.push_str("§r"),
+            }
+            for (set, code) in [
+                (style.bold, 'l'),
+                (style.strikethrough, 'm'),
+                (style.underlined, 'n'),
+                (style.italic, 'o'),
+                (style.obfuscated, 'k'),
+            ] {
+                if set {
+                    output.push('§');
+                    output.push(code);
+                }
+            }
+            current = style;
+        }
+        output.push_str(&context.fill(&part.text));
+    }
+    output
+}
+
+fn parse_json(input: &str) -> Option<Value> {
+    let trimmed = input.trim_start();
+    if !trimmed.starts_with('{') && !trimmed.starts_with('[') {
+        return None;
+    }
+    serde_json::from_str(trimmed).ok()
+}
+
+/// Splits legacy codes and MiniMessage tags into styled runs of text.
+fn parse(input: &str) -> Vec<Component> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut parts: Vec<Component> = Vec::new();
+    let mut style = Style::default();
+    let mut stack: Vec<Style> = Vec::new();
+    let mut text = String::new();
+    let mut i = 0;
+
+    let mut flush = |text: &mut String, style: &Style| {
+        if !text.is_empty() {
+            parts.push(Component::styled(std::mem::take(text), style));
+        }
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        if (c == '§' || c == '&') && i + 1 < chars.len() {
+            // Bukkit hex: §x§r§r§g§g§b§b
+            if chars[i + 1].eq_ignore_ascii_case(&'x') {
+                if let Some(hex) = bukkit_hex(&chars[i..]) {
+                    flush(&mut text, &style);
+                    style = Style { color: Some(format!("#{}", hex)), ..Style::default() };
+                    i += 14;
+                    continue;
+                }
+            }
+            // &#rrggbb
+            if chars[i + 1] == '#' {
+                let hex: String = chars[i + 2..].iter().take(6).collect();
+                if hex.len() == 6 && hex.chars().all(|h| h.is_ascii_hexdigit()) {
+                    flush(&mut text, &style);
+                    style = Style { color: Some(format!("#{}", hex.to_lowercase())), ..Style::default() };
+                    i += 8;
+                    continue;
+                }
+            }
+            let code = chars[i + 1].to_ascii_lowercase();
+            if let Some(next) = apply_legacy(&style, code) {
+                flush(&mut text, &style);
+                style = next;
+                i += 2;
+                continue;
+            }
+        }
+        if c == '<' {
+            if let Some(end) = chars[i..].iter().position(|&c| c == '>') {
+                let tag: String = chars[i + 1..i + end].iter().collect();
+                if let Some(action) = parse_tag(&tag) {
+                    flush(&mut text, &style);
+                    match action {
+                        Tag::Open(next) => {
+                            stack.push(style.clone());
+                            style = next(&style);
+                        }
+                        Tag::Close => style = stack.pop().unwrap_or_default(),
+                        Tag::Reset => {
+                            stack.clear();
+                            style = Style::default();
+                        }
+                        Tag::Newline => text.push('\n'),
+                    }
+                    i += end + 1;
+                    continue;
+                }
+            }
+        }
+        text.push(c);
+        i += 1;
+    }
+    flush(&mut text, &style);
+    parts
+}
+
+fn bukkit_hex(chars: &[char]) -> Option<String> {
+    if chars.len() < 14 {
+        return None;
+    }
+    let mut hex = String::new();
+    for pair in chars[2..14].chunks(2) {
+        if !matches!(pair[0], '§' | '&') || !pair[1].is_ascii_hexdigit() {
+            return None;
+        }
+        hex.push(pair[1].to_ascii_lowercase());
+    }
+    Some(hex)
+}
+
+/// The style after a legacy code, or `None` if `code` is not one.
+fn apply_legacy(style: &Style, code: char) -> Option<Style> {
+    if let Some((_, name, _)) = COLORS.iter().find(|(c, _, _)| *c == code) {
+        return Some(Style { color: Some(name.to_string()), ..Style::default() });
+    }
+    let mut next = style.clone();
+    match code {
+        'k' => next.obfuscated = true,
+        'l' => next.bold = true,
+        'm' => next.strikethrough = true,
+        'n' => next.underlined = true,
+        'o' => next.italic = true,
+        'r' => next = Style::default(),
+        _ => return None,
+    }
+    Some(next)
+}
+
+enum Tag {
+    Open(Box<dyn Fn(&Style) -> Style>),
+    Close,
+    Reset,
+    Newline,
+}
+
+fn parse_tag(tag: &str) -> Option<Tag> {
+    let tag = tag.trim().to_ascii_lowercase();
+    match tag.as_str() {
+        "reset" => return Some(Tag::Reset),
+        "newline" | "br" => return Some(Tag::Newline),
+        _ => {}
+    }
+    if let Some(closing) = tag.strip_prefix('/') {
+        return parse_tag(closing).and_then(|tag| match tag {
+            Tag::Open(_) => Some(Tag::Close),
+            _ => None,
+        });
+    }
+    let color = tag.strip_prefix("color:").or_else(|| tag.strip_prefix("c:")).unwrap_or(&tag);
+    if let Some(color) = parse_color(color) {
+        return Some(Tag::Open(Box::new(move |style| Style { color: Some(color.clone()), ..style.clone() })));
+    }
+    let mut probe = Style::default();
+    if probe.set_decoration(&tag, true) {
+        return Some(Tag::Open(Box::new(move |style| {
+            let mut next = style.clone();
+            next.set_decoration(&tag, true);
+            next
+        })));
+    }
+    None
+}
+
+fn parse_color(color: &str) -> Option<String> {
+    if let Some(hex) = color.strip_prefix('#') {
+        if hex.len() == 6 && hex.chars().all(|h| h.is_ascii_hexdigit()) {
+            return Some(format!("#{}", hex));
+        }
+        return None;
+    }
+    let color = if color == "grey" { "gray" } else { color };
+    COLORS.iter().find(|(_, name, _)| *name == color).map(|(_, name, _)| name.to_string())
+}
+
+/// Legacy code of the named color closest to `color`.
+fn nearest_named(color: &str) -> char {
+    if let Some((code, _, _)) = COLORS.iter().find(|(_, name, _)| *name == color) {
+        return *code;
+    }
+    let rgb = color
+        .strip_prefix('#')
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .unwrap_or(0xFFFFFF);
+    let channels = |rgb: u32| [(rgb >> 16) as i32 & 0xFF, (rgb >> 8) as i32 & 0xFF, rgb as i32 & 0xFF];
+    let target = channels(rgb);
+    COLORS
+        .iter()
+        .min_by_key(|(_, _, candidate)| {
+            channels(*candidate)
+                .iter()
+                .zip(target.iter())
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<i32>()
+        })
+        .map(|(code, _, _)| *code)
+        .unwrap_or('f')
+}
+
+/// Fills placeholders in every string of a component except its colors.
+fn fill(value: &mut Value, context: &Context) {
+    match value {
+        Value::String(text) => *text = context.fill(text),
+        Value::Object(map) => map
+            .iter_mut()
+            .filter(|(key, _)| key.as_str() != "color")
+            .for_each(|(_, value)| fill(value, context)),
+        Value::Array(items) => items.iter_mut().for_each(|item| fill(item, context)),
+        _ => {}
+    }
+}
+
+/// Replaces hex colors anywhere in a component with the nearest named color.
+fn downsample(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            if let Some(Value::String(color)) = map.get_mut("color") {
+                if color.starts_with('#') {
+                    let code = nearest_named(color);
+                    if let Some((_, name, _)) = COLORS.iter().find(|(c, _, _)| *c == code) {
+                        *color = name.to_string();
+                    }
+                }
+            }
+            map.values_mut().for_each(downsample);
+        }
+        Value::Array(items) => items.iter_mut().for_each(downsample),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_placeholders_in_one_pass() {
+        let context = Context::new(767)
+            .with("username", "{server_id}")
+            .with("server_id", "lobby")
+            .with("hostname", "{username}");
+        assert_eq!(context.fill("{username} on {server_id}"), "{server_id} on lobby");
+        assert_eq!(context.fill("{hostname}/{username}"), "{username}/{server_id}");
+    }
+
+    #[test]
+    fn keeps_unknown_and_unclosed_placeholders() {
+        let context = Context::new(767).with("server_id", "lobby");
+        assert_eq!(context.fill("no placeholders"), "no placeholders");
+        assert_eq!(context.fill("{unknown} {server_id}"), "{unknown} lobby");
+        assert_eq!(context.fill("{{server_id}}"), "{lobby}");
+        assert_eq!(context.fill("{server_id"), "{server_id");
+        assert_eq!(context.fill("{ünï} {server_id} {"), "{ünï} lobby {");
+    }
+
+    fn text(text: &str, color: Option<&str>, bold: bool) -> Component {
+        Component {
+            text: text.to_string(),
+            color: color.map(str::to_string),
+            bold: if bold { Some(true) } else { None },
+            ..Component::default()
+        }
+    }
+
+    #[test]
+    fn escapes_quotes_backslashes_and_newlines() {
+        let context = Context::new(767).with("username", "a\"b\\");
+        let json = component("say \"hi\" \\ there\n{username}", &context).to_string();
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["extra"][0]["text"], "say \"hi\" \\ there\na\"b\\");
+    }
+
+    #[test]
+    fn parses_legacy_codes() {
+        assert_eq!(
+            parse("§cRed &lbold&rplain"),
+            vec![text("Red ", Some("red"), false), text("bold", Some("red"), true), text("plain", None, false)]
+        );
+        assert_eq!(parse("&#FF8800orange"), vec![text("orange", Some("#ff8800"), false)]);
+        assert_eq!(parse("§x§F§F§8§8§0§0orange"), vec![text("orange", Some("#ff8800"), false)]);
+        assert_eq!(parse("50& more &z"), vec![text("50& more &z", None, false)]);
+    }
+
+    #[test]
+    fn parses_minimessage_tags() {
+        assert_eq!(
+            parse("<red>a<bold>b</bold>c</red>d"),
+            vec![
+                text("a", Some("red"), false),
+                text("b", Some("red"), true),
+                text("c", Some("red"), false),
+                text("d", None, false),
+            ]
+        );
+        assert_eq!(parse("<#FF8800>x<reset>y"), vec![text("x", Some("#ff8800"), false), text("y", None, false)]);
+        assert_eq!(parse("<color:grey>a<br>b"), vec![text("a", Some("gray"), false), text("\nb", Some("gray"), false)]);
+        assert_eq!(parse("1 <unknown> 2"), vec![text("1 <unknown> 2", None, false)]);
+    }
+
+    #[test]
+    fn passes_raw_json_through() {
+        let context = Context::new(767).with("username", "Steve");
+        let input = r##"{"text":"{username}","color":"#ff8800","extra":[{"text":"&c"}]}"##;
+        assert_eq!(
+            component(input, &context),
+            serde_json::json!({"text": "Steve", "color": "#ff8800", "extra": [{"text": "&c"}]})
+        );
+        // Not valid JSON, so it is read as text
+        assert_eq!(component("{username", &context)["extra"][0]["text"], "{username");
+    }
+
+    #[test]
+    fn downsamples_hex_colors_for_old_clients() {
+        let input = r##"{"text":"x","color":"#ff8800","extra":[{"text":"y","color":"#ff0000"}]}"##;
+        let old = component(input, &Context::new(HEX_COLOR_PROTOCOL - 1));
+        assert_eq!(old["color"], "gold");
+        assert_eq!(old["extra"][0]["color"], "dark_red");
+        assert_eq!(component(input, &Context::new(HEX_COLOR_PROTOCOL))["color"], "#ff8800");
+
+        assert_eq!(component("&#ff8800x", &Context::new(340))["extra"][0]["color"], "gold");
+        assert_eq!(legacy("&#ff8800Hi <bold>x", &Context::new(340)), "§6Hi §6§lx");
+    }
+}
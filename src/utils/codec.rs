@@ -0,0 +1,811 @@
+use anyhow::Result;
+use bytes::{Buf, BufMut};
+
+/// Longest string the protocol allows, in UTF-16 code units
+pub const MAX_STRING_LENGTH: usize = 32767;
+/// How deep NBT compounds and lists may nest, as in vanilla
+const MAX_NBT_DEPTH: usize = 512;
+
+/// Reads a VarInt from the front of `buf`, failing instead of panicking when
+/// it runs out.
+pub fn get_var_int(buf: &mut impl Buf) -> Result<i32> {
+    Ok(get_var(buf, 5)? as i32)
+}
+
+pub fn get_var_long(buf: &mut impl Buf) -> Result<i64> {
+    Ok(get_var(buf, 10)? as i64)
+}
+
+fn get_var(buf: &mut impl Buf, max_bytes: u32) -> Result<u64> {
+    let mut result = 0u64;
+    for shift in 0..max_bytes {
+        if !buf.has_remaining() {
+            return Err(anyhow!("Packet ended inside a VarInt"));
+        }
+        let byte = buf.get_u8();
+        result |= ((byte & 0b0111_1111) as u64) << (7 * shift);
+        if byte & 0b1000_0000 == 0 {
+            return Ok(result);
+        }
+    }
+    Err(anyhow!("VarInt longer than {} bytes", max_bytes))
+}
+
+pub fn put_var_int(buf: &mut impl BufMut, value: i32) {
+    put_var(buf, value as u32 as u64);
+}
+
+pub fn put_var_long(buf: &mut impl BufMut, value: i64) {
+    put_var(buf, value as u64);
+}
+
+fn put_var(buf: &mut impl BufMut, mut value: u64) {
+    let mut bytes = [0u8; 10];
+    let mut length = 0;
+    loop {
+        let byte = (value & 0b0111_1111) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes[length] = byte;
+            buf.put_slice(&bytes[..=length]);
+            return;
+        }
+        bytes[length] = byte | 0b1000_0000;
+        length += 1;
+    }
+}
+
+/// Puts `packet` behind its length, as it goes on the wire without
+/// compression.
+pub fn put_frame(buf: &mut impl BufMut, packet: &[u8]) {
+    put_var_int(buf, packet.len() as i32);
+    buf.put_slice(packet);
+}
+
+/// A block position, packed into a long on the wire.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub struct Position {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl Position {
+    /// Packs the position as 1.14 and later do: 26 bits of x, 26 of z, 12 of y.
+    pub fn pack(&self) -> i64 {
+        ((self.x as i64 & 0x3FF_FFFF) << 38) | ((self.z as i64 & 0x3FF_FFFF) << 12) | (self.y as i64 & 0xFFF)
+    }
+
+    pub fn unpack(packed: i64) -> Self {
+        Self {
+            x: (packed >> 38) as i32,
+            y: (packed << 52 >> 52) as i32,
+            z: (packed << 26 >> 38) as i32,
+        }
+    }
+}
+
+/// An NBT tag. Compounds keep their entries in the order they were read.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Nbt {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<u8>),
+    String(String),
+    /// Written with the type of its first element, or as a list of ends when
+    /// empty
+    List(Vec<Nbt>),
+    Compound(Vec<(String, Nbt)>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Nbt {
+    fn tag(&self) -> u8 {
+        match self {
+            Nbt::Byte(_) => 1,
+            Nbt::Short(_) => 2,
+            Nbt::Int(_) => 3,
+            Nbt::Long(_) => 4,
+            Nbt::Float(_) => 5,
+            Nbt::Double(_) => 6,
+            Nbt::ByteArray(_) => 7,
+            Nbt::String(_) => 8,
+            Nbt::List(_) => 9,
+            Nbt::Compound(_) => 10,
+            Nbt::IntArray(_) => 11,
+            Nbt::LongArray(_) => 12,
+        }
+    }
+}
+
+/// Fewest bytes a payload of tag `tag` can take, so that a count can be
+/// checked against what is left before anything is allocated.
+fn min_payload_size(tag: u8) -> usize {
+    match tag {
+        1 | 10 => 1,
+        2 | 8 => 2,
+        3 | 5 | 7 | 11 | 12 => 4,
+        9 => 5,
+        4 | 6 => 8,
+        _ => 0,
+    }
+}
+
+/// Reads protocol types from a packet. Every read checks what is left first,
+/// so a lying length prefix fails instead of allocating.
+#[derive(Debug, Clone)]
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Fails if anything is left, for packets that must be read whole.
+    pub fn finish(&self) -> Result<()> {
+        match self.buf.len() {
+            0 => Ok(()),
+            left => Err(anyhow!("{} unexpected bytes at the end of the packet", left)),
+        }
+    }
+
+    /// Takes everything that is left, for fields that run to the end.
+    pub fn read_rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.buf)
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        if count > self.buf.len() {
+            return Err(anyhow!("Packet ended {} bytes early", count - self.buf.len()));
+        }
+        let (taken, rest) = self.buf.split_at(count);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into()?)
+    }
+
+    /// Reads a count and checks that `min_size` bytes per item are left.
+    fn count(&mut self, count: i64, min_size: usize) -> Result<usize> {
+        let count = usize::try_from(count).map_err(|_| anyhow!("Negative length {}", count))?;
+        if count.saturating_mul(min_size) > self.buf.len() {
+            return Err(anyhow!("Length {} runs past the end of the packet", count));
+        }
+        Ok(count)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(anyhow!("{} is not a boolean", other)),
+        }
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_i8(&mut self) -> Result<i8> {
+        Ok(self.read_u8()? as i8)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    pub fn read_i16(&mut self) -> Result<i16> {
+        Ok(i16::from_be_bytes(self.array()?))
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32> {
+        Ok(i32::from_be_bytes(self.array()?))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64> {
+        Ok(i64::from_be_bytes(self.array()?))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32> {
+        Ok(f32::from_be_bytes(self.array()?))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64> {
+        Ok(f64::from_be_bytes(self.array()?))
+    }
+
+    pub fn read_var_int(&mut self) -> Result<i32> {
+        get_var_int(&mut self.buf)
+    }
+
+    pub fn read_var_long(&mut self) -> Result<i64> {
+        get_var_long(&mut self.buf)
+    }
+
+    pub fn read_uuid(&mut self) -> Result<u128> {
+        Ok(u128::from_be_bytes(self.array()?))
+    }
+
+    pub fn read_position(&mut self) -> Result<Position> {
+        Ok(Position::unpack(self.read_i64()?))
+    }
+
+    /// Reads a string of at most `max_length` UTF-16 code units, as the
+    /// protocol counts them.
+    pub fn read_string(&mut self, max_length: usize) -> Result<String> {
+        let length = self.read_var_int()?;
+        let length = self.count(length as i64, 1)?;
+        if length > max_length * 3 {
+            return Err(anyhow!("String of {} bytes is longer than {} characters", length, max_length));
+        }
+        let string = std::str::from_utf8(self.take(length)?)?;
+        if string.encode_utf16().count() > max_length {
+            return Err(anyhow!("String is longer than {} characters", max_length));
+        }
+        Ok(string.to_string())
+    }
+
+    /// Reads a length-prefixed byte array of at most `max_length` bytes.
+    pub fn read_byte_array(&mut self, max_length: usize) -> Result<&'a [u8]> {
+        let length = self.read_var_int()?;
+        let length = self.count(length as i64, 1)?;
+        if length > max_length {
+            return Err(anyhow!("Byte array of {} bytes is longer than {}", length, max_length));
+        }
+        self.take(length)
+    }
+
+    /// Reads NBT as sent over the network since 1.20.2: a root tag without a
+    /// name. A lone end tag means there is none.
+    pub fn read_nbt(&mut self) -> Result<Option<Nbt>> {
+        match self.read_u8()? {
+            0 => Ok(None),
+            tag => self.read_nbt_payload(tag, 0).map(Some),
+        }
+    }
+
+    /// Reads NBT as sent before 1.20.2 and stored on disk, with a named root.
+    pub fn read_named_nbt(&mut self) -> Result<Option<(String, Nbt)>> {
+        match self.read_u8()? {
+            0 => Ok(None),
+            tag => {
+                let name = self.read_nbt_string()?;
+                Ok(Some((name, self.read_nbt_payload(tag, 0)?)))
+            }
+        }
+    }
+
+    fn read_nbt_payload(&mut self, tag: u8, depth: usize) -> Result<Nbt> {
+        if depth > MAX_NBT_DEPTH {
+            return Err(anyhow!("NBT nested deeper than {}", MAX_NBT_DEPTH));
+        }
+        Ok(match tag {
+            1 => Nbt::Byte(self.read_i8()?),
+            2 => Nbt::Short(self.read_i16()?),
+            3 => Nbt::Int(self.read_i32()?),
+            4 => Nbt::Long(self.read_i64()?),
+            5 => Nbt::Float(self.read_f32()?),
+            6 => Nbt::Double(self.read_f64()?),
+            7 => {
+                let length = self.read_i32()?;
+                let length = self.count(length as i64, 1)?;
+                Nbt::ByteArray(self.take(length)?.to_vec())
+            }
+            8 => Nbt::String(self.read_nbt_string()?),
+            9 => {
+                let element = self.read_u8()?;
+                let length = self.read_i32()?;
+                let length = self.count(length as i64, min_payload_size(element))?;
+                if element == 0 && length > 0 {
+                    return Err(anyhow!("NBT list of {} end tags", length));
+                }
+                let mut list = Vec::with_capacity(length);
+                for _ in 0..length {
+                    list.push(self.read_nbt_payload(element, depth + 1)?);
+                }
+                Nbt::List(list)
+            }
+            10 => {
+                let mut entries = Vec::new();
+                loop {
+                    match self.read_u8()? {
+                        0 => break,
+                        tag => {
+                            let name = self.read_nbt_string()?;
+                            entries.push((name, self.read_nbt_payload(tag, depth + 1)?));
+                        }
+                    }
+                }
+                Nbt::Compound(entries)
+            }
+            11 => {
+                let length = self.read_i32()?;
+                let length = self.count(length as i64, 4)?;
+                Nbt::IntArray((0..length).map(|_| self.read_i32()).collect::<Result<_>>()?)
+            }
+            12 => {
+                let length = self.read_i32()?;
+                let length = self.count(length as i64, 8)?;
+                Nbt::LongArray((0..length).map(|_| self.read_i64()).collect::<Result<_>>()?)
+            }
+            other => return Err(anyhow!("Unknown NBT tag {}", other)),
+        })
+    }
+
+    /// NBT strings are Java's modified UTF-8 behind an unsigned short length.
+    fn read_nbt_string(&mut self) -> Result<String> {
+        let length = self.read_u16()? as usize;
+        let bytes = self.take(length)?;
+        let malformed = || anyhow!("Malformed modified UTF-8 in NBT string");
+        let continuation = |at: usize| match bytes.get(at) {
+            Some(byte) if byte & 0b1100_0000 == 0b1000_0000 => Ok((byte & 0b0011_1111) as u16),
+            _ => Err(malformed()),
+        };
+        let mut units = Vec::with_capacity(length);
+        let mut at = 0;
+        while let Some(&byte) = bytes.get(at) {
+            let (unit, size) = match byte {
+                0x01..=0x7F => (byte as u16, 1),
+                0xC0..=0xDF => (((byte & 0b0001_1111) as u16) << 6 | continuation(at + 1)?, 2),
+                0xE0..=0xEF => (
+                    ((byte & 0b0000_1111) as u16) << 12 | continuation(at + 1)? << 6 | continuation(at + 2)?,
+                    3,
+                ),
+                _ => return Err(malformed()),
+            };
+            units.push(unit);
+            at += size;
+        }
+        Ok(String::from_utf16(&units)?)
+    }
+}
+
+/// Writes protocol types into a packet, by default a fresh `Vec`, or any
+/// `BufMut` such as a reused `BytesMut`. Lengths are not checked against the
+/// protocol's limits; that is left to whoever reads them.
+#[derive(Debug, Clone, Default)]
+pub struct Writer<B = Vec<u8>> {
+    buf: B,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::wrap(Vec::with_capacity(capacity))
+    }
+}
+
+impl<B: BufMut> Writer<B> {
+    pub fn wrap(buf: B) -> Self {
+        Self { buf }
+    }
+
+    pub fn into_inner(self) -> B {
+        self.buf
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buf.put_slice(bytes);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.buf.put_u8(value as u8);
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.put_u8(value);
+    }
+
+    pub fn write_i8(&mut self, value: i8) {
+        self.buf.put_i8(value);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.buf.put_u16(value);
+    }
+
+    pub fn write_i16(&mut self, value: i16) {
+        self.buf.put_i16(value);
+    }
+
+    pub fn write_i32(&mut self, value: i32) {
+        self.buf.put_i32(value);
+    }
+
+    pub fn write_i64(&mut self, value: i64) {
+        self.buf.put_i64(value);
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.buf.put_f32(value);
+    }
+
+    pub fn write_f64(&mut self, value: f64) {
+        self.buf.put_f64(value);
+    }
+
+    pub fn write_var_int(&mut self, value: i32) {
+        put_var_int(&mut self.buf, value);
+    }
+
+    pub fn write_var_long(&mut self, value: i64) {
+        put_var_long(&mut self.buf, value);
+    }
+
+    pub fn write_uuid(&mut self, value: u128) {
+        self.buf.put_u128(value);
+    }
+
+    pub fn write_position(&mut self, value: Position) {
+        self.write_i64(value.pack());
+    }
+
+    pub fn write_string(&mut self, value: &str) {
+        self.write_byte_array(value.as_bytes());
+    }
+
+    pub fn write_byte_array(&mut self, value: &[u8]) {
+        put_frame(&mut self.buf, value);
+    }
+
+    /// Writes NBT in the nameless network form of 1.20.2 and later.
+    pub fn write_nbt(&mut self, value: Option<&Nbt>) -> Result<()> {
+        match value {
+            None => self.write_u8(0),
+            Some(nbt) => {
+                self.write_u8(nbt.tag());
+                self.write_nbt_payload(nbt)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes NBT with a named root, as before 1.20.2.
+    pub fn write_named_nbt(&mut self, name: &str, value: &Nbt) -> Result<()> {
+        self.write_u8(value.tag());
+        self.write_nbt_string(name)?;
+        self.write_nbt_payload(value)
+    }
+
+    fn write_nbt_payload(&mut self, value: &Nbt) -> Result<()> {
+        match value {
+            Nbt::Byte(v) => self.write_i8(*v),
+            Nbt::Short(v) => self.write_i16(*v),
+            Nbt::Int(v) => self.write_i32(*v),
+            Nbt::Long(v) => self.write_i64(*v),
+            Nbt::Float(v) => self.write_f32(*v),
+            Nbt::Double(v) => self.write_f64(*v),
+            Nbt::ByteArray(v) => {
+                self.write_i32(v.len() as i32);
+                self.write_bytes(v);
+            }
+            Nbt::String(v) => self.write_nbt_string(v)?,
+            Nbt::List(list) => {
+                let element = list.first().map_or(0, Nbt::tag);
+                if list.iter().any(|item| item.tag() != element) {
+                    return Err(anyhow!("NBT list mixes tag types"));
+                }
+                self.write_u8(element);
+                self.write_i32(list.len() as i32);
+                for item in list {
+                    self.write_nbt_payload(item)?;
+                }
+            }
+            Nbt::Compound(entries) => {
+                for (name, item) in entries {
+                    self.write_u8(item.tag());
+                    self.write_nbt_string(name)?;
+                    self.write_nbt_payload(item)?;
+                }
+                self.write_u8(0);
+            }
+            Nbt::IntArray(v) => {
+                self.write_i32(v.len() as i32);
+                v.iter().for_each(|item| self.write_i32(*item));
+            }
+            Nbt::LongArray(v) => {
+                self.write_i32(v.len() as i32);
+                v.iter().for_each(|item| self.write_i64(*item));
+            }
+        }
+        Ok(())
+    }
+
+    fn write_nbt_string(&mut self, value: &str) -> Result<()> {
+        let mut bytes = Vec::with_capacity(value.len());
+        for unit in value.encode_utf16() {
+            match unit {
+                0x01..=0x7F => bytes.push(unit as u8),
+                0x00 | 0x80..=0x7FF => bytes.extend([0xC0 | (unit >> 6) as u8, 0x80 | (unit & 0x3F) as u8]),
+                _ => bytes.extend([
+                    0xE0 | (unit >> 12) as u8,
+                    0x80 | ((unit >> 6) & 0x3F) as u8,
+                    0x80 | (unit & 0x3F) as u8,
+                ]),
+            }
+        }
+        let length = u16::try_from(bytes.len()).map_err(|_| anyhow!("NBT string of {} bytes is too long", bytes.len()))?;
+        self.write_u16(length);
+        self.write_bytes(&bytes);
+        Ok(())
+    }
+}
+
+/// A packet of a known state and direction, with its id.
+pub trait Packet: Sized {
+    const ID: i32;
+
+    /// Writes the fields after the id.
+    fn encode<B: BufMut>(&self, writer: &mut Writer<B>);
+
+    /// Reads the fields after the id.
+    fn decode(reader: &mut Reader) -> Result<Self>;
+
+    /// The id and fields, ready for a `PacketWriter`.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.write_var_int(Self::ID);
+        self.encode(&mut writer);
+        writer.into_inner()
+    }
+
+    /// Parses a packet from a `PacketReader`, which must have this id and
+    /// nothing after its fields.
+    fn from_bytes(packet: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(packet);
+        let id = reader.read_var_int()?;
+        if id != Self::ID {
+            let name = std::any::type_name::<Self>().rsplit("::").next().unwrap_or_default();
+            return Err(anyhow!("{} is not a id of {} packet", id, name));
+        }
+        let packet = Self::decode(&mut reader)?;
+        reader.finish()?;
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var_int_bytes(value: i32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_var_int(&mut buf, value);
+        buf
+    }
+
+    #[test]
+    fn var_int_round_trips() {
+        let edges = [0, 1, -1, 127, 128, 25565, i32::MIN, i32::MAX];
+        for value in edges.into_iter().chain((0..10_000).map(|_| rand::random())) {
+            let buf = var_int_bytes(value);
+            let mut input = &buf[..];
+            assert_eq!(get_var_int(&mut input).unwrap(), value);
+            assert!(input.is_empty(), "{} left bytes behind", value);
+        }
+        assert_eq!(var_int_bytes(0), [0x00]);
+        assert_eq!(var_int_bytes(-1), [0xFF, 0xFF, 0xFF, 0xFF, 0x0F]);
+        assert_eq!(var_int_bytes(i32::MIN), [0x80, 0x80, 0x80, 0x80, 0x08]);
+    }
+
+    #[test]
+    fn var_long_round_trips() {
+        let edges = [0, 1, -1, i64::from(i32::MIN), i64::MIN, i64::MAX];
+        for value in edges.into_iter().chain((0..10_000).map(|_| rand::random())) {
+            let mut buf = Vec::new();
+            put_var_long(&mut buf, value);
+            assert!(buf.len() <= 10);
+            let mut input = &buf[..];
+            assert_eq!(get_var_long(&mut input).unwrap(), value);
+            assert!(input.is_empty(), "{} left bytes behind", value);
+        }
+    }
+
+    #[test]
+    fn var_int_rejects_truncated_and_overlong() {
+        for buf in [&[][..], &[0x80], &[0xFF, 0xFF, 0xFF, 0xFF]] {
+            assert!(get_var_int(&mut &buf[..]).is_err(), "{:?}", buf);
+        }
+        assert!(get_var_int(&mut &[0xFF; 6][..]).is_err());
+        assert!(get_var_long(&mut &[0xFF; 11][..]).is_err());
+    }
+
+    fn string_round_trip(value: &str, max_length: usize) -> Result<String> {
+        let mut writer = Writer::new();
+        writer.write_string(value);
+        let buf = writer.into_inner();
+        let mut reader = Reader::new(&buf);
+        let string = reader.read_string(max_length)?;
+        reader.finish()?;
+        Ok(string)
+    }
+
+    #[test]
+    fn strings_up_to_the_utf16_limit() {
+        for value in ["", "play.example.com", "ünïcødé", "😀"] {
+            assert_eq!(string_round_trip(value, MAX_STRING_LENGTH).unwrap(), value);
+        }
+        // One code unit each, up to three bytes each
+        for unit in ["a", "é", "€"] {
+            let at_limit = unit.repeat(MAX_STRING_LENGTH);
+            assert_eq!(string_round_trip(&at_limit, MAX_STRING_LENGTH).unwrap(), at_limit);
+            assert!(string_round_trip(&unit.repeat(MAX_STRING_LENGTH + 1), MAX_STRING_LENGTH).is_err());
+        }
+        // Two code units each, so the limit is reached before the byte limit
+        let pairs = "😀".repeat(MAX_STRING_LENGTH / 2);
+        assert_eq!(string_round_trip(&pairs, MAX_STRING_LENGTH).unwrap(), pairs);
+        assert!(string_round_trip(&"😀".repeat(MAX_STRING_LENGTH / 2 + 1), MAX_STRING_LENGTH).is_err());
+        assert!(string_round_trip("seventeen letters", 16).is_err());
+    }
+
+    #[test]
+    fn uuid_and_position_round_trip() {
+        let positions = [
+            Position { x: 0, y: 0, z: 0 },
+            Position { x: -1, y: -1, z: -1 },
+            Position { x: -33_554_432, y: -2048, z: -33_554_432 },
+            Position { x: 33_554_431, y: 2047, z: 33_554_431 },
+            Position { x: -18_357_644, y: -64, z: 831 },
+        ];
+        let random = (0..1000).map(|_| Position {
+            x: rand::random::<i32>() >> 6,
+            y: rand::random::<i32>() >> 20,
+            z: rand::random::<i32>() >> 6,
+        });
+        for position in positions.into_iter().chain(random) {
+            let uuid = rand::random::<u128>();
+            let mut writer = Writer::new();
+            writer.write_uuid(uuid);
+            writer.write_position(position);
+            let buf = writer.into_inner();
+            assert_eq!(buf.len(), 24);
+            let mut reader = Reader::new(&buf);
+            assert_eq!(reader.read_uuid().unwrap(), uuid);
+            assert_eq!(reader.read_position().unwrap(), position);
+            reader.finish().unwrap();
+        }
+    }
+
+    fn sample_nbt() -> Nbt {
+        Nbt::Compound(vec![
+            ("byte".to_string(), Nbt::Byte(-128)),
+            ("short".to_string(), Nbt::Short(i16::MIN)),
+            ("int".to_string(), Nbt::Int(-1)),
+            ("long".to_string(), Nbt::Long(i64::MAX)),
+            ("float".to_string(), Nbt::Float(-0.5)),
+            ("double".to_string(), Nbt::Double(std::f64::consts::PI)),
+            ("bytes".to_string(), Nbt::ByteArray(vec![0, 1, 255])),
+            ("nul\0and 😀".to_string(), Nbt::String("a\0b 😀 \u{10FFFF} é".to_string())),
+            ("empty".to_string(), Nbt::List(Vec::new())),
+            (
+                "nested".to_string(),
+                Nbt::List(vec![
+                    Nbt::Compound(vec![("text".to_string(), Nbt::String("hi".to_string()))]),
+                    Nbt::Compound(Vec::new()),
+                ]),
+            ),
+            ("ints".to_string(), Nbt::IntArray(vec![i32::MIN, 0, i32::MAX])),
+            ("longs".to_string(), Nbt::LongArray(vec![i64::MIN, 0, i64::MAX])),
+        ])
+    }
+
+    #[test]
+    fn nbt_round_trips() {
+        let nbt = sample_nbt();
+
+        let mut writer = Writer::new();
+        writer.write_nbt(Some(&nbt)).unwrap();
+        let buf = writer.into_inner();
+        let mut reader = Reader::new(&buf);
+        assert_eq!(reader.read_nbt().unwrap(), Some(nbt.clone()));
+        reader.finish().unwrap();
+
+        let mut writer = Writer::new();
+        writer.write_named_nbt("root", &nbt).unwrap();
+        let buf = writer.into_inner();
+        let mut reader = Reader::new(&buf);
+        assert_eq!(reader.read_named_nbt().unwrap(), Some(("root".to_string(), nbt)));
+        reader.finish().unwrap();
+
+        let mut writer = Writer::new();
+        writer.write_nbt(None).unwrap();
+        assert_eq!(Reader::new(&writer.into_inner()).read_nbt().unwrap(), None);
+    }
+
+    #[test]
+    fn nbt_strings_use_modified_utf8() {
+        let mut writer = Writer::new();
+        writer.write_named_nbt("", &Nbt::String("\0😀".to_string())).unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            8, 0, 0, 0, 8,
+            // NUL takes two bytes, so the string never contains a zero byte
+            0xC0, 0x80,
+            // Supplementary characters are a surrogate pair, three bytes each
+            0xED, 0xA0, 0xBD, 0xED, 0xB8, 0x80,
+        ];
+        assert_eq!(writer.into_inner(), expected);
+
+        // Plain UTF-8 for a supplementary character is not modified UTF-8
+        let mut buf = vec![8, 0, 0, 0, 4];
+        buf.extend("😀".as_bytes());
+        assert!(Reader::new(&buf).read_named_nbt().is_err());
+        assert!(Reader::new(&[8, 0, 0, 0, 1, 0x00]).read_named_nbt().is_err());
+    }
+
+    #[test]
+    fn nbt_rejects_mixed_lists() {
+        let list = Nbt::List(vec![Nbt::Int(1), Nbt::Long(1)]);
+        assert!(Writer::new().write_nbt(Some(&list)).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let mut writer = Writer::new();
+        writer.write_string("play.example.com");
+        writer.write_nbt(Some(&sample_nbt())).unwrap();
+        writer.write_uuid(u128::MAX);
+        let buf = writer.into_inner();
+        for end in 0..buf.len() {
+            let mut reader = Reader::new(&buf[..end]);
+            let read = reader
+                .read_string(MAX_STRING_LENGTH)
+                .and_then(|_| reader.read_nbt())
+                .and_then(|_| reader.read_uuid());
+            assert!(read.is_err(), "{} of {} bytes read fine", end, buf.len());
+        }
+    }
+
+    #[test]
+    fn rejects_lengths_past_the_buffer() {
+        // A string and a byte array claiming 100 bytes with 3 present
+        let mut buf = var_int_bytes(100);
+        buf.extend(b"abc");
+        assert!(Reader::new(&buf).read_string(MAX_STRING_LENGTH).is_err());
+        assert!(Reader::new(&buf).read_byte_array(1024).is_err());
+        // Negative lengths
+        let mut buf = var_int_bytes(-1);
+        buf.extend(b"abc");
+        assert!(Reader::new(&buf).read_string(MAX_STRING_LENGTH).is_err());
+        assert!(Reader::new(&buf).read_byte_array(1024).is_err());
+
+        // NBT arrays and lists whose counts would not fit in what is left
+        for (tag, element) in [(7, None), (9, Some(3)), (11, None), (12, None)] {
+            for count in [i32::MAX, -1, 2] {
+                let mut buf = vec![tag];
+                buf.extend(element);
+                buf.extend(count.to_be_bytes());
+                buf.push(0);
+                assert!(Reader::new(&buf).read_nbt().is_err(), "tag {} count {}", tag, count);
+            }
+        }
+        // An NBT string claiming more bytes than follow
+        assert!(Reader::new(&[8, 0xFF, 0xFF, b'a']).read_nbt().is_err());
+    }
+
+    #[test]
+    fn rejects_nbt_nested_too_deep() {
+        let mut buf = vec![9];
+        for _ in 0..=MAX_NBT_DEPTH {
+            buf.extend([9, 0, 0, 0, 1]);
+        }
+        buf.extend([0, 0, 0, 0, 0]);
+        assert!(Reader::new(&buf).read_nbt().is_err());
+    }
+}
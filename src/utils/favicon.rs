@@ -0,0 +1,46 @@
+use anyhow::Result;
+use base64::Engine;
+use image::imageops::FilterType;
+use image::ImageFormat;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+/// Side length of a server list icon
+const SIZE: u32 = 64;
+
+/// Encoded icons by path, with the modification time they were read at.
+static CACHE: OnceLock<Mutex<HashMap<PathBuf, (SystemTime, String)>>> = OnceLock::new();
+
+/// Returns the image at `path` as a `data:image/png;base64,` URI for a status
+/// response, scaled to 64x64 if needed. Files are only decoded again once they
+/// change on disk.
+pub fn load(path: &Path) -> Result<String> {
+    let modified = std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    let cache = CACHE.get_or_init(Default::default);
+    if let Some((cached_at, uri)) = cache.lock().unwrap().get(path) {
+        if *cached_at == modified {
+            return Ok(uri.clone());
+        }
+    }
+    let uri = encode(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    cache.lock().unwrap().insert(path.to_path_buf(), (modified, uri.clone()));
+    Ok(uri)
+}
+
+fn encode(path: &Path) -> Result<String> {
+    let mut image = image::open(path)?;
+    if image.width() != SIZE || image.height() != SIZE {
+        image = image.resize_exact(SIZE, SIZE, FilterType::Lanczos3);
+    }
+    let mut png = Vec::new();
+    image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    Ok(format!(
+        "data:image/png;base64,{}",
+        base64::engine::general_purpose::STANDARD.encode(png)
+    ))
+}
@@ -1,54 +1,304 @@
+use crate::utils::codec::{self, Packet, Reader, Writer, MAX_STRING_LENGTH};
 use anyhow::Result;
+use bytes::{BufMut, BytesMut};
 use derive_more::Display;
-use std::io::Cursor;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 
+/// Reads a frame length straight from the stream. It goes a byte at a time so
+/// that nothing after the VarInt is consumed; what follows is read in one go
+/// and decoded with `codec`.
 pub async fn read_var_int<T>(stream: &mut T) -> Result<i32>
 where
     T: AsyncRead + std::marker::Unpin,
 {
-    let mut num_read: i32 = 0;
-    let mut result: i32 = 0;
-    loop {
-        let read = stream.read_u8().await? as i32;
-        let value = read & 0b0111_1111;
-        result |= value << (7 * num_read);
-        num_read += 1;
-        if num_read > 5 {
-            return Err(anyhow!("VarInt too big!"));
-        }
-        if (read & 0b1000_0000) == 0 {
-            break;
+    let mut bytes = [0u8; 5];
+    for length in 1..=bytes.len() {
+        bytes[length - 1] = stream.read_u8().await?;
+        if bytes[length - 1] & 0b1000_0000 == 0 {
+            return codec::get_var_int(&mut &bytes[..length]);
         }
     }
-    Ok(result)
+    Err(anyhow!("VarInt too big!"))
+}
+
+/// Reads one length-prefixed packet and returns it still framed, so it can be
+/// replayed to a backend byte for byte.
+pub async fn read_frame<T>(stream: &mut T) -> Result<Vec<u8>>
+where
+    T: AsyncRead + std::marker::Unpin,
+{
+    let size = read_var_int(stream).await?;
+    if !(0..=MAX_PACKET_SIZE as i32).contains(&size) {
+        return Err(anyhow!("Invalid packet size {}", size));
+    }
+    let mut frame = Vec::with_capacity(size as usize + 3);
+    codec::put_var_int(&mut frame, size);
+    let start = frame.len();
+    frame.resize(start + size as usize, 0);
+    stream.read_exact(&mut frame[start..]).await?;
+    Ok(frame)
+}
+
+/// Largest packet the protocol allows on the wire, 2^21 - 1 bytes
+pub const MAX_PACKET_SIZE: usize = 2_097_151;
+/// Largest size a compressed packet may claim once inflated, 2^23 bytes
+pub const MAX_DATA_LENGTH: usize = 8_388_608;
+
+/// Reads packets as their id and data, inflating them once compression has
+/// been switched on. Packets are read into buffers that are kept for the next
+/// one.
+#[derive(Debug)]
+pub struct PacketReader<R> {
+    inner: R,
+    threshold: Option<usize>,
+    frame: Vec<u8>,
+    inflated: Vec<u8>,
 }
 
-pub async fn write_var_int<T>(stream: &mut T, mut value: i32) -> Result<()>
+impl<R> PacketReader<R>
 where
-    T: AsyncWrite + std::marker::Unpin,
+    R: AsyncRead + std::marker::Unpin,
 {
-    loop {
-        let mut temp: i16 = (value & 0b0111_1111) as i16;
-        value >>= 7;
-        if value != 0 {
-            temp |= 0b1000_0000;
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            threshold: None,
+            frame: Vec::new(),
+            inflated: Vec::new(),
+        }
+    }
+
+    /// Switches framing as a Set Compression packet does. A negative
+    /// threshold turns compression off again.
+    pub fn set_compression(&mut self, threshold: i32) {
+        self.threshold = usize::try_from(threshold).ok();
+    }
+
+    /// Reads the next packet. It is only valid until the next read.
+    pub async fn read_packet(&mut self) -> Result<&[u8]> {
+        let length = read_var_int(&mut self.inner).await?;
+        let length = usize::try_from(length)
+            .ok()
+            .filter(|length| *length <= MAX_PACKET_SIZE)
+            .ok_or_else(|| anyhow!("Invalid packet size {}", length))?;
+        self.frame.resize(length, 0);
+        self.inner.read_exact(&mut self.frame).await?;
+        let Some(threshold) = self.threshold else {
+            return Ok(&self.frame);
+        };
+
+        let mut body = Reader::new(&self.frame);
+        let data_length = body.read_var_int()?;
+        if data_length == 0 {
+            return Ok(body.read_rest());
         }
-        stream.write_i8(temp as i8).await?;
-        if value == 0 {
-            break Ok(());
+        let data_length = usize::try_from(data_length)
+            .ok()
+            .filter(|data_length| (threshold..=MAX_DATA_LENGTH).contains(data_length))
+            .ok_or_else(|| anyhow!("Invalid data length {} for threshold {}", data_length, threshold))?;
+        self.inflated.clear();
+        ZlibDecoder::new(body.read_rest())
+            .take(data_length as u64 + 1)
+            .read_to_end(&mut self.inflated)?;
+        if self.inflated.len() != data_length {
+            return Err(anyhow!(
+                "Packet inflated to {} bytes, expected {}",
+                self.inflated.len(),
+                data_length
+            ));
         }
+        Ok(&self.inflated)
     }
 }
-pub async fn read_string<T>(stream: &mut T) -> Result<String>
+
+/// Room kept in front of a packet encoded by `PacketWriter::send` for its
+/// length, at most three bytes, and the data length of an uncompressed packet.
+const HEADROOM: usize = 4;
+
+/// Writes packets given as their id and data, deflating those at or above the
+/// threshold once compression has been switched on. Packets are framed in a
+/// buffer that is kept for the next one, and each goes out in a single write.
+#[derive(Debug)]
+pub struct PacketWriter<W> {
+    inner: W,
+    threshold: Option<usize>,
+    frame: BytesMut,
+}
+
+impl<W> PacketWriter<W>
 where
-    T: AsyncRead + std::marker::Unpin,
+    W: AsyncWrite + std::marker::Unpin,
 {
-    let length = read_var_int(stream).await?;
-    let mut buf = vec![0u8; length as usize];
-    stream.read_exact(&mut buf).await?;
-    Ok(String::from_utf8_lossy(&buf).to_string())
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            threshold: None,
+            frame: BytesMut::new(),
+        }
+    }
+
+    /// Switches framing as a Set Compression packet does. A negative
+    /// threshold turns compression off again.
+    pub fn set_compression(&mut self, threshold: i32) {
+        self.threshold = usize::try_from(threshold).ok();
+    }
+
+    pub async fn write_packet(&mut self, packet: &[u8]) -> Result<()> {
+        self.frame.clear();
+        match self.threshold {
+            None => codec::put_frame(&mut self.frame, packet),
+            Some(threshold) if packet.len() < threshold => {
+                codec::put_var_int(&mut self.frame, packet.len() as i32 + 1);
+                self.frame.put_u8(0);
+                self.frame.put_slice(packet);
+            }
+            Some(_) => {
+                let mut compressed = Writer::new();
+                compressed.write_var_int(packet.len() as i32);
+                let mut encoder = ZlibEncoder::new(compressed.into_inner(), Compression::default());
+                encoder.write_all(packet)?;
+                codec::put_frame(&mut self.frame, &encoder.finish()?);
+            }
+        }
+        if self.frame.len() > MAX_PACKET_SIZE + 3 {
+            return Err(anyhow!("Packet of {} bytes is too big to send", self.frame.len()));
+        }
+        self.inner.write_all(&self.frame).await?;
+        Ok(())
+    }
+
+    /// Encodes `packet` straight into the frame, behind room for its header.
+    pub async fn send<P: Packet>(&mut self, packet: &P) -> Result<()> {
+        self.frame.clear();
+        self.frame.put_bytes(0, HEADROOM);
+        let mut writer = Writer::wrap(&mut self.frame);
+        writer.write_var_int(P::ID);
+        packet.encode(&mut writer);
+        let length = self.frame.len() - HEADROOM;
+        if length > MAX_PACKET_SIZE - 1 {
+            return Err(anyhow!("Packet of {} bytes is too big to send", length));
+        }
+        let start = match self.threshold {
+            None => prepend_var_int(&mut self.frame[..HEADROOM], length),
+            Some(threshold) if length < threshold => {
+                self.frame[HEADROOM - 1] = 0;
+                prepend_var_int(&mut self.frame[..HEADROOM - 1], length + 1)
+            }
+            Some(_) => {
+                let packet = self.frame.split_off(HEADROOM);
+                return self.write_packet(&packet).await;
+            }
+        };
+        self.inner.write_all(&self.frame[start..]).await?;
+        Ok(())
+    }
+}
+
+/// Writes `value` as a VarInt at the end of `room` and returns where it starts.
+fn prepend_var_int(room: &mut [u8], value: usize) -> usize {
+    let mut bytes = [0u8; 5];
+    let unused = {
+        let mut unused = &mut bytes[..];
+        codec::put_var_int(&mut unused, value as i32);
+        unused.len()
+    };
+    let bytes = &bytes[..bytes.len() - unused];
+    let start = room.len() - bytes.len();
+    room[start..].copy_from_slice(bytes);
+    start
+}
+
+/// Longest name a Login Start may carry
+const MAX_USERNAME_LENGTH: usize = 16;
+
+/// The first packet of the login state, kept framed for replaying.
+#[derive(Debug, Clone)]
+pub struct LoginStart {
+    frame: Vec<u8>,
+    username: String,
+}
+
+impl LoginStart {
+    pub async fn read<T>(stream: &mut T) -> Result<Self>
+    where
+        T: AsyncRead + std::marker::Unpin,
+    {
+        let frame = read_frame(stream).await?;
+        let mut body = Reader::new(&frame);
+        body.read_var_int()?;
+        let id = body.read_var_int()?;
+        if id != 0 {
+            return Err(anyhow!("{} is not a id of login start packet", id));
+        }
+        let username = body.read_string(MAX_USERNAME_LENGTH)?;
+        Ok(Self { frame, username })
+    }
+
+    pub fn get_username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn get_frame(&self) -> &[u8] {
+        &self.frame
+    }
+}
+
+/// Marker a Forge client appends to the handshake address, `\0FML\0` and so on.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Display)]
+pub enum ForgeMarker {
+    /// Forge for 1.7 to 1.12
+    #[display(fmt = "FML")]
+    Fml,
+    /// Forge for 1.13 to 1.17
+    #[display(fmt = "FML2")]
+    Fml2,
+    /// Forge for 1.18 and later
+    #[display(fmt = "FML3")]
+    Fml3,
+}
+
+impl ForgeMarker {
+    fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "FML" => Some(Self::Fml),
+            "FML2" => Some(Self::Fml2),
+            "FML3" => Some(Self::Fml3),
+            _ => None,
+        }
+    }
+}
+
+/// Starts the envelope a TCPShield edge appends to the address
+const ENVELOPE_SEPARATOR: &str = "///";
+
+/// Splits a handshake address into the hostname, the Forge marker and the
+/// null-delimited fields after the hostname, marker included. An edge such as
+/// TCPShield appends its `///` envelope after the last field, so that is moved
+/// back onto the hostname it belongs to.
+fn split_address(address: &str) -> (String, Option<ForgeMarker>, Vec<String>) {
+    let mut fields = address.split('\0');
+    let mut hostname = fields.next().unwrap_or_default().to_string();
+    let mut marker = None;
+    let mut rest = Vec::new();
+    for field in fields {
+        let field = match field.find(ENVELOPE_SEPARATOR) {
+            Some(at) => {
+                hostname.push_str(&field[at..]);
+                &field[..at]
+            }
+            None => field,
+        };
+        if marker.is_none() {
+            marker = ForgeMarker::from_tag(field);
+        }
+        rest.push(field.to_string());
+    }
+    (hostname, marker, rest)
 }
 
 #[derive(Debug, Clone)]
@@ -58,6 +308,9 @@ pub struct HandshakeRequest {
     id: i32,
     version: i32,
     host: String,
+    hostname: String,
+    forge: Option<ForgeMarker>,
+    fields: Vec<String>,
     port: u16,
     next_state: NextState,
 }
@@ -65,32 +318,92 @@ pub struct HandshakeRequest {
 impl HandshakeRequest {
     pub async fn read(stream: &mut TcpStream) -> Result<Self> {
         let size = read_var_int(stream).await?;
+        if !(0..=MAX_PACKET_SIZE as i32).contains(&size) {
+            return Err(anyhow!("Invalid packet size {}", size));
+        }
         let mut raw_body = vec![0u8; size as usize];
         stream.read_exact(&mut raw_body).await?;
-        let mut raw_body = Cursor::new(raw_body);
-        let id = read_var_int(&mut raw_body).await?;
+        let mut body = Reader::new(&raw_body);
+        let id = body.read_var_int()?;
         if id != 0 {
             return Err(anyhow!("{} is not a id of handshake packet", id));
         }
-        let version = read_var_int(&mut raw_body).await?;
-        let host = read_string(&mut raw_body).await?;
-        let port = raw_body.read_u16().await?;
-        let next_state = NextState::from_i32(read_var_int(&mut raw_body).await?)?;
+        let version = body.read_var_int()?;
+        let host = body.read_string(MAX_STRING_LENGTH)?;
+        let port = body.read_u16()?;
+        let next_state = NextState::from_i32(body.read_var_int()?)?;
+        let (hostname, forge, fields) = split_address(&host);
         Ok(Self {
             size,
             id,
             version,
             host,
+            hostname,
+            forge,
+            fields,
             port,
             next_state,
-            raw_body: raw_body.into_inner(),
+            raw_body,
         })
     }
 
+    pub fn get_id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn get_version(&self) -> i32 {
+        self.version
+    }
+
+    /// The address exactly as the client sent it, markers included.
     pub fn get_host(&self) -> &str {
         &self.host
     }
 
+    /// The address without the null-delimited fields after it. A TCPShield
+    /// envelope is still attached.
+    pub fn get_hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    pub fn get_forge(&self) -> Option<ForgeMarker> {
+        self.forge
+    }
+
+    /// The null-delimited fields after the hostname, Forge marker included.
+    pub fn get_fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// The same handshake addressed to `hostname`, with the fields after the
+    /// hostname kept as they were. Used to pass on the address without the
+    /// envelope an edge added to it.
+    pub fn with_hostname(&self, hostname: &str) -> Self {
+        let host = std::iter::once(hostname)
+            .chain(self.fields.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join("\0");
+        let mut body = Writer::with_capacity(self.raw_body.len());
+        body.write_var_int(self.id);
+        body.write_var_int(self.version);
+        body.write_string(&host);
+        body.write_u16(self.port);
+        body.write_var_int(self.next_state.to_i32());
+        let raw_body = body.into_inner();
+        Self {
+            size: raw_body.len() as i32,
+            raw_body,
+            id: self.id,
+            version: self.version,
+            host,
+            hostname: hostname.to_string(),
+            forge: self.forge,
+            fields: self.fields.clone(),
+            port: self.port,
+            next_state: self.next_state.clone(),
+        }
+    }
+
     pub fn get_next_state(&self) -> &NextState {
         &self.next_state
     }
@@ -122,4 +435,217 @@ impl NextState {
             _ => return Err(anyhow!("Cannot convert {} to NextState", num)),
         })
     }
+
+    pub fn to_i32(&self) -> i32 {
+        match self {
+            Self::Status => 1,
+            Self::Login => 2,
+        }
+    }
+}
+
+/// Answer to a status request, the server list entry as JSON.
+#[derive(Debug, Clone)]
+pub struct StatusResponse {
+    pub json: String,
+}
+
+impl Packet for StatusResponse {
+    const ID: i32 = 0;
+
+    fn encode<B: BufMut>(&self, writer: &mut Writer<B>) {
+        writer.write_string(&self.json);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(Self { json: reader.read_string(MAX_STRING_LENGTH)? })
+    }
+}
+
+/// Longest disconnect reason a client accepts
+const MAX_REASON_LENGTH: usize = 262144;
+
+/// Turns a client away during login, with a chat component as JSON.
+#[derive(Debug, Clone)]
+pub struct LoginDisconnect {
+    pub reason: String,
+}
+
+impl Packet for LoginDisconnect {
+    const ID: i32 = 0;
+
+    fn encode<B: BufMut>(&self, writer: &mut Writer<B>) {
+        writer.write_string(&self.reason);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(Self { reason: reader.read_string(MAX_REASON_LENGTH)? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_the_address_into_hostname_and_fields() {
+        let cases: [(&str, &str, Option<ForgeMarker>, &[&str]); 6] = [
+            ("play.example.com", "play.example.com", None, &[]),
+            ("play.example.com\0FML\0", "play.example.com", Some(ForgeMarker::Fml), &["FML", ""]),
+            ("play.example.com\0FML3\0", "play.example.com", Some(ForgeMarker::Fml3), &["FML3", ""]),
+            ("a.example\0X\0", "a.example", None, &["X", ""]),
+            ("a.example\0X\0FML2\0", "a.example", Some(ForgeMarker::Fml2), &["X", "FML2", ""]),
+            ("a.example\0FML\0FML2", "a.example", Some(ForgeMarker::Fml), &["FML", "FML2"]),
+        ];
+        for (address, hostname, marker, fields) in cases {
+            let fields = fields.iter().map(|f| f.to_string()).collect();
+            assert_eq!(split_address(address), (hostname.to_string(), marker, fields), "{:?}", address);
+        }
+    }
+
+    #[test]
+    fn rebuilds_the_handshake_for_another_hostname() {
+        let mut body = Writer::new();
+        body.write_var_int(0);
+        body.write_var_int(767);
+        body.write_string("a.example\0FML3\0///203.0.113.7:51234///1700000000///c2ln");
+        body.write_u16(25565);
+        body.write_var_int(2);
+        let raw_body = body.into_inner();
+        let mut reader = Reader::new(&raw_body);
+        reader.read_var_int().unwrap();
+        let version = reader.read_var_int().unwrap();
+        let host = reader.read_string(MAX_STRING_LENGTH).unwrap();
+        let (hostname, forge, fields) = split_address(&host);
+        let handshake = HandshakeRequest {
+            size: raw_body.len() as i32,
+            raw_body,
+            id: 0,
+            version,
+            host,
+            hostname,
+            forge,
+            fields,
+            port: 25565,
+            next_state: NextState::Login,
+        };
+
+        let rebuilt = handshake.with_hostname("a.example");
+        assert_eq!(rebuilt.get_host(), "a.example\0FML3\0");
+        assert_eq!(rebuilt.get_forge(), Some(ForgeMarker::Fml3));
+        let mut reader = Reader::new(rebuilt.get_raw_body());
+        assert_eq!(reader.read_var_int().unwrap(), 0);
+        assert_eq!(reader.read_var_int().unwrap(), 767);
+        assert_eq!(reader.read_string(MAX_STRING_LENGTH).unwrap(), "a.example\0FML3\0");
+        assert_eq!(reader.read_u16().unwrap(), 25565);
+        assert_eq!(reader.read_var_int().unwrap(), 2);
+        reader.finish().unwrap();
+        assert_eq!(rebuilt.get_size() as usize, rebuilt.get_raw_body().len());
+    }
+
+    #[test]
+    fn keeps_an_envelope_on_the_hostname() {
+        let envelope = "///203.0.113.7:51234///1700000000///c2ln";
+        assert_eq!(
+            split_address(&format!("a.example{}", envelope)),
+            (format!("a.example{}", envelope), None, Vec::new())
+        );
+        assert_eq!(
+            split_address(&format!("a.example\0FML2\0{}", envelope)),
+            (format!("a.example{}", envelope), Some(ForgeMarker::Fml2), vec!["FML2".to_string(), String::new()])
+        );
+    }
+
+    /// Frames `packet` as compressed while claiming `data_length` inflated bytes.
+    fn compressed_frame(data_length: i32, packet: &[u8]) -> Vec<u8> {
+        let mut body = Writer::new();
+        body.write_var_int(data_length);
+        let mut encoder = ZlibEncoder::new(body.into_inner(), Compression::default());
+        encoder.write_all(packet).unwrap();
+        let mut frame = Vec::new();
+        codec::put_frame(&mut frame, &encoder.finish().unwrap());
+        frame
+    }
+
+    fn packet(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn round_trips_packets_around_the_threshold() {
+        let packets = [packet(1), packet(255), packet(256), packet(5000)];
+        let mut writer = PacketWriter::new(Vec::new());
+        writer.set_compression(256);
+        for packet in &packets {
+            writer.write_packet(packet).await.unwrap();
+        }
+        let wire = writer.inner;
+        // Below the threshold a packet is sent as is behind a zero data length
+        assert_eq!(&wire[..3], &[2, 0, 0]);
+
+        let mut reader = PacketReader::new(&wire[..]);
+        reader.set_compression(256);
+        for packet in &packets {
+            assert_eq!(reader.read_packet().await.unwrap(), &packet[..]);
+        }
+        assert!(reader.read_packet().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn switches_compression_mid_stream() {
+        let mut writer = PacketWriter::new(Vec::new());
+        writer.write_packet(&packet(300)).await.unwrap();
+        writer.set_compression(64);
+        writer.write_packet(&packet(10)).await.unwrap();
+        writer.write_packet(&packet(300)).await.unwrap();
+        writer.set_compression(-1);
+        writer.write_packet(&packet(300)).await.unwrap();
+        let wire = writer.inner;
+
+        let mut reader = PacketReader::new(&wire[..]);
+        assert_eq!(reader.read_packet().await.unwrap(), &packet(300)[..]);
+        reader.set_compression(64);
+        assert_eq!(reader.read_packet().await.unwrap(), &packet(10)[..]);
+        assert_eq!(reader.read_packet().await.unwrap(), &packet(300)[..]);
+        reader.set_compression(-1);
+        assert_eq!(reader.read_packet().await.unwrap(), &packet(300)[..]);
+    }
+
+    #[tokio::test]
+    async fn rejects_data_length_below_the_threshold() {
+        let frame = compressed_frame(10, &packet(10));
+        let mut reader = PacketReader::new(&frame[..]);
+        reader.set_compression(64);
+        let err = reader.read_packet().await.unwrap_err();
+        assert_eq!(err.to_string(), "Invalid data length 10 for threshold 64");
+    }
+
+    #[tokio::test]
+    async fn rejects_data_length_that_does_not_match() {
+        for (claimed, actual) in [(200, 100), (100, 200)] {
+            let frame = compressed_frame(claimed, &packet(actual));
+            let mut reader = PacketReader::new(&frame[..]);
+            reader.set_compression(64);
+            let err = reader.read_packet().await.unwrap_err();
+            assert!(err.to_string().starts_with("Packet inflated to"), "{}", err);
+        }
+
+        let frame = compressed_frame(MAX_DATA_LENGTH as i32 + 1, &packet(100));
+        let mut reader = PacketReader::new(&frame[..]);
+        reader.set_compression(64);
+        assert!(reader.read_packet().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_frames() {
+        let mut wire = Vec::new();
+        codec::put_var_int(&mut wire, MAX_PACKET_SIZE as i32 + 1);
+        let mut reader = PacketReader::new(&wire[..]);
+        let err = reader.read_packet().await.unwrap_err();
+        assert_eq!(err.to_string(), format!("Invalid packet size {}", MAX_PACKET_SIZE + 1));
+
+        let mut writer = PacketWriter::new(Vec::new());
+        assert!(writer.write_packet(&packet(MAX_PACKET_SIZE + 1)).await.is_err());
+        assert!(writer.inner.is_empty());
+    }
 }
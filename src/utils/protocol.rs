@@ -0,0 +1,221 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Java Edition releases and their protocol numbers, oldest first.
+const VERSIONS: &[(&str, i32)] = &[
+    ("1.7.2", 4),
+    ("1.7.4", 4),
+    ("1.7.5", 4),
+    ("1.7.6", 5),
+    ("1.7.10", 5),
+    ("1.8", 47),
+    ("1.8.9", 47),
+    ("1.9", 107),
+    ("1.9.1", 108),
+    ("1.9.2", 109),
+    ("1.9.3", 110),
+    ("1.9.4", 110),
+    ("1.10", 210),
+    ("1.10.2", 210),
+    ("1.11", 315),
+    ("1.11.1", 316),
+    ("1.11.2", 316),
+    ("1.12", 335),
+    ("1.12.1", 338),
+    ("1.12.2", 340),
+    ("1.13", 393),
+    ("1.13.1", 401),
+    ("1.13.2", 404),
+    ("1.14", 477),
+    ("1.14.1", 480),
+    ("1.14.2", 485),
+    ("1.14.3", 490),
+    ("1.14.4", 498),
+    ("1.15", 573),
+    ("1.15.1", 575),
+    ("1.15.2", 578),
+    ("1.16", 735),
+    ("1.16.1", 736),
+    ("1.16.2", 751),
+    ("1.16.3", 753),
+    ("1.16.4", 754),
+    ("1.16.5", 754),
+    ("1.17", 755),
+    ("1.17.1", 756),
+    ("1.18", 757),
+    ("1.18.1", 757),
+    ("1.18.2", 758),
+    ("1.19", 759),
+    ("1.19.1", 760),
+    ("1.19.2", 760),
+    ("1.19.3", 761),
+    ("1.19.4", 762),
+    ("1.20", 763),
+    ("1.20.1", 763),
+    ("1.20.2", 764),
+    ("1.20.3", 765),
+    ("1.20.4", 765),
+    ("1.20.5", 766),
+    ("1.20.6", 766),
+    ("1.21", 767),
+    ("1.21.1", 767),
+    ("1.21.2", 768),
+    ("1.21.3", 768),
+    ("1.21.4", 769),
+    ("1.21.5", 770),
+    ("1.21.6", 771),
+    ("1.21.7", 772),
+    ("1.21.8", 772),
+    ("1.21.9", 773),
+    ("1.21.10", 773),
+];
+
+/// Friendly name for a protocol number, e.g. `1.20.3-1.20.4` for 765.
+pub fn version_name(protocol: i32) -> Option<String> {
+    let mut names = VERSIONS.iter().filter(|(_, p)| *p == protocol).map(|(name, _)| *name);
+    let first = names.next()?;
+    Some(match names.next_back() {
+        Some(last) => format!("{}-{}", first, last),
+        None => first.to_string(),
+    })
+}
+
+/// Protocol numbers covered by a release name. `1.20` covers every 1.20.x.
+fn protocols_of(name: &str) -> Option<(i32, i32)> {
+    let prefix = format!("{}.", name);
+    let mut protocols = VERSIONS
+        .iter()
+        .filter(|(n, _)| *n == name || n.starts_with(&prefix))
+        .map(|(_, p)| *p);
+    let first = protocols.next()?;
+    Some((first, protocols.next_back().unwrap_or(first)))
+}
+
+/// Protocols for one side of a range: a release name or a raw number.
+fn parse_bound(bound: &str) -> Result<(i32, i32), String> {
+    let bound = bound.trim();
+    if let Ok(protocol) = bound.parse::<i32>() {
+        return Ok((protocol, protocol));
+    }
+    protocols_of(bound).ok_or_else(|| format!("unknown version {:?}", bound))
+}
+
+/// Inclusive range of protocol numbers, written as release names or numbers:
+/// `1.8-1.21.4`, `1.20.5+`, `1.12` or `47-340`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct VersionRange {
+    spec: String,
+    min: i32,
+    max: i32,
+}
+
+impl VersionRange {
+    pub fn contains(&self, protocol: i32) -> bool {
+        (self.min..=self.max).contains(&protocol)
+    }
+
+    /// Protocol to report in a status response so that clients outside the
+    /// range see it as incompatible.
+    pub fn advertised(&self, protocol: i32) -> i32 {
+        protocol.clamp(self.min, self.max)
+    }
+}
+
+impl FromStr for VersionRange {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (min, max) = if let Some(lower) = spec.trim().strip_suffix('+') {
+            (parse_bound(lower)?.0, i32::MAX)
+        } else if let Some((lower, upper)) = spec.split_once('-') {
+            (parse_bound(lower)?.0, parse_bound(upper)?.1)
+        } else {
+            parse_bound(spec)?
+        };
+        if min > max {
+            return Err(format!("{:?} ends before it starts", spec));
+        }
+        Ok(Self { spec: spec.trim().to_string(), min, max })
+    }
+}
+
+impl TryFrom<String> for VersionRange {
+    type Error = String;
+
+    fn try_from(spec: String) -> Result<Self, Self::Error> {
+        spec.parse()
+    }
+}
+
+impl From<VersionRange> for String {
+    fn from(range: VersionRange) -> Self {
+        range.spec
+    }
+}
+
+impl fmt::Display for VersionRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(spec: &str) -> VersionRange {
+        spec.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_range_forms() {
+        let cases = [
+            ("1.8-1.21.4", 47, 769),
+            ("1.20.5+", 766, i32::MAX),
+            ("47-340", 47, 340),
+            ("1.12", 335, 340),
+            ("1.20", 763, 766),
+            ("767", 767, 767),
+            (" 1.8 - 1.12.2 ", 47, 340),
+            ("47+", 47, i32::MAX),
+        ];
+        for (spec, min, max) in cases {
+            let parsed = range(spec);
+            assert_eq!((parsed.min, parsed.max), (min, max), "{:?}", spec);
+        }
+        assert_eq!(range(" 1.8 - 1.12.2 ").to_string(), "1.8 - 1.12.2");
+
+        let modern = range("1.8-1.21.4");
+        assert!(modern.contains(47) && modern.contains(769));
+        assert!(!modern.contains(46) && !modern.contains(770));
+    }
+
+    #[test]
+    fn rejects_unknown_versions_and_reversed_ranges() {
+        assert_eq!("1.99".parse::<VersionRange>().unwrap_err(), "unknown version \"1.99\"");
+        assert_eq!("1.8-1.99".parse::<VersionRange>().unwrap_err(), "unknown version \"1.99\"");
+        assert!("beta+".parse::<VersionRange>().is_err());
+        assert!("".parse::<VersionRange>().is_err());
+        assert_eq!("1.21-1.8".parse::<VersionRange>().unwrap_err(), "\"1.21-1.8\" ends before it starts");
+        assert!("340-47".parse::<VersionRange>().is_err());
+        assert!(serde_yaml::from_str::<VersionRange>("1.99+").is_err());
+    }
+
+    #[test]
+    fn clamps_the_advertised_protocol() {
+        let legacy = range("1.8-1.12.2");
+        assert_eq!(legacy.advertised(767), 340);
+        assert_eq!(legacy.advertised(4), 47);
+        assert_eq!(legacy.advertised(110), 110);
+        assert_eq!(range("1.20.5+").advertised(767), 767);
+    }
+
+    #[test]
+    fn names_protocols() {
+        assert_eq!(version_name(765).as_deref(), Some("1.20.3-1.20.4"));
+        assert_eq!(version_name(340).as_deref(), Some("1.12.2"));
+        assert_eq!(version_name(9999), None);
+    }
+}
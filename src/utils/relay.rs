@@ -0,0 +1,138 @@
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+/// Size of the userspace buffer when splice(2) is not used
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// Moves everything `reader` receives to `writer` until it closes, then shuts
+/// `writer` down. With `splice` set, and where the platform allows it, the
+/// bytes go through a pipe with splice(2) and never enter userspace. Returns
+/// how many bytes were moved, along with the error that cut it short, if any.
+pub async fn relay(mut reader: OwnedReadHalf, mut writer: OwnedWriteHalf, splice: bool) -> (u64, io::Result<()>) {
+    let (moved, result) = move_all(&mut reader, &mut writer, splice).await;
+    match result {
+        Ok(()) => (moved, writer.shutdown().await),
+        Err(e) => (moved, Err(e)),
+    }
+}
+
+async fn move_all(reader: &mut OwnedReadHalf, writer: &mut OwnedWriteHalf, splice: bool) -> (u64, io::Result<()>) {
+    if splice {
+        match pipe::Pipe::new() {
+            Ok(pipe) => return pipe::splice_all(reader, writer, pipe).await,
+            Err(e) => debug!("Falling back to copying, splice(2) is unavailable: {}", e),
+        }
+    }
+    copy(reader, writer).await
+}
+
+/// Whether `relay` can splice on this system.
+pub fn splice_available() -> bool {
+    pipe::Pipe::new().is_ok()
+}
+
+/// Plain copy through a buffer. `tokio::io::copy` would do, but it does not
+/// say how far it got when it fails.
+async fn copy(reader: &mut OwnedReadHalf, writer: &mut OwnedWriteHalf) -> (u64, io::Result<()>) {
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    let mut moved = 0;
+    loop {
+        let read = match reader.read(&mut buf).await {
+            Ok(0) => return (moved, Ok(())),
+            Ok(read) => read,
+            Err(e) => return (moved, Err(e)),
+        };
+        if let Err(e) = writer.write_all(&buf[..read]).await {
+            return (moved, Err(e));
+        }
+        moved += read as u64;
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod pipe {
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use tokio::io::Interest;
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+    /// Bytes asked for per splice call, the default pipe capacity
+    const CHUNK: usize = 64 * 1024;
+
+    /// A non-blocking pipe, closed on drop.
+    pub struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+    }
+
+    impl Pipe {
+        pub fn new() -> io::Result<Self> {
+            let mut fds = [0; 2];
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: pipe2 succeeded, so both descriptors are open and ours.
+            Ok(unsafe {
+                Self {
+                    read: OwnedFd::from_raw_fd(fds[0]),
+                    write: OwnedFd::from_raw_fd(fds[1]),
+                }
+            })
+        }
+    }
+
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+        let moved = unsafe { libc::splice(from, std::ptr::null_mut(), to, std::ptr::null_mut(), len, flags) };
+        if moved == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(moved as usize)
+    }
+
+    /// Splices from the socket into the pipe and straight on into the other
+    /// socket. The pipe is always drained before the next read, so a
+    /// `WouldBlock` can only come from the socket being waited on.
+    pub async fn splice_all(reader: &OwnedReadHalf, writer: &OwnedWriteHalf, pipe: Pipe) -> (u64, io::Result<()>) {
+        let (from, to) = (reader.as_ref(), writer.as_ref());
+        let mut moved = 0;
+        loop {
+            let read = from.async_io(Interest::READABLE, || splice(from.as_raw_fd(), pipe.write.as_raw_fd(), CHUNK));
+            let mut pending = match read.await {
+                Ok(0) => return (moved, Ok(())),
+                Ok(read) => read,
+                Err(e) => return (moved, Err(e)),
+            };
+            while pending > 0 {
+                let write = to.async_io(Interest::WRITABLE, || splice(pipe.read.as_raw_fd(), to.as_raw_fd(), pending));
+                match write.await {
+                    Ok(written) => {
+                        pending -= written;
+                        moved += written as u64;
+                    }
+                    Err(e) => return (moved, Err(e)),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod pipe {
+    use std::io;
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+    /// splice(2) is Linux only, so there is never a pipe to use.
+    pub enum Pipe {}
+
+    impl Pipe {
+        pub fn new() -> io::Result<Self> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "splice(2) is Linux only"))
+        }
+    }
+
+    pub async fn splice_all(_: &OwnedReadHalf, _: &OwnedWriteHalf, pipe: Pipe) -> (u64, io::Result<()>) {
+        match pipe {}
+    }
+}
@@ -0,0 +1,281 @@
+use anyhow::Result;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+/// A minimal HTTP/1.1 client for APIs that listen on a unix socket, such as
+/// the Docker Engine. Each request uses its own connection.
+pub async fn request(socket: &Path, method: &str, path: &str, body: Option<&str>) -> Result<(u16, String)> {
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .map_err(|e| anyhow!("Failed to connect to {}: {}", socket.display(), e))?;
+
+    let body = body.unwrap_or("");
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    parse_response(&response)
+}
+
+/// Sends a GET and calls `on_line` for every newline-delimited line of the
+/// (usually chunked) response body as it arrives, for streaming endpoints such
+/// as Docker's `/events`. Returns when the server closes the stream.
+pub async fn stream_lines(socket: &Path, path: &str, mut on_line: impl FnMut(&str)) -> Result<()> {
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .map_err(|e| anyhow!("Failed to connect to {}: {}", socket.display(), e))?;
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let status = line.split_whitespace().nth(1).and_then(|c| c.parse::<u16>().ok());
+    if status != Some(200) {
+        return Err(anyhow!("Unexpected response: {}", line.trim()));
+    }
+    let mut chunked = false;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        let header = line.trim().to_ascii_lowercase();
+        if header.is_empty() {
+            break;
+        }
+        if header.starts_with("transfer-encoding:") && header.contains("chunked") {
+            chunked = true;
+        }
+    }
+
+    let mut pending: Vec<u8> = Vec::new();
+    loop {
+        if chunked {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+            let size = usize::from_str_radix(line.trim().split(';').next().unwrap_or(""), 16)?;
+            if size == 0 {
+                return Ok(());
+            }
+            let mut chunk = vec![0u8; size + 2];
+            reader.read_exact(&mut chunk).await?;
+            chunk.truncate(size);
+            pending.extend_from_slice(&chunk);
+        } else {
+            let mut buf = [0u8; 4096];
+            let read = reader.read(&mut buf).await?;
+            if read == 0 {
+                return Ok(());
+            }
+            pending.extend_from_slice(&buf[..read]);
+        }
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if !line.trim().is_empty() {
+                on_line(line.trim());
+            }
+        }
+    }
+}
+
+fn parse_response(response: &[u8]) -> Result<(u16, String)> {
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| anyhow!("Malformed HTTP response"))?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let body = &response[end + 4..];
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("Malformed HTTP status line"))?;
+
+    let chunked = head.lines().any(|line| {
+        let line = line.to_ascii_lowercase();
+        line.starts_with("transfer-encoding:") && line.contains("chunked")
+    });
+    // Chunk sizes count bytes, so the body is only decoded as text once whole
+    let body = if chunked { decode_chunked(body)? } else { body.to_vec() };
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(|| anyhow!("Truncated chunked body"))?;
+        let size = std::str::from_utf8(&body[..line_end])?;
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16)?;
+        if size == 0 {
+            return Ok(decoded);
+        }
+        let rest = &body[line_end + 2..];
+        let chunk = rest
+            .get(..size)
+            .ok_or_else(|| anyhow!("Truncated chunk"))?;
+        decoded.extend_from_slice(chunk);
+        body = rest[size..].strip_prefix(b"\r\n").unwrap_or(&rest[size..]);
+    }
+}
+
+/// A stand-in for a unix socket API, for tests.
+#[cfg(test)]
+pub mod fake {
+    use std::future::Future;
+    use std::path::PathBuf;
+    use tokio::io::{AsyncRead, AsyncReadExt};
+    use tokio::net::{UnixListener, UnixStream};
+
+    /// Listens on a fresh socket named after `name` and hands every request,
+    /// read whole, to `handler` along with the connection to answer on.
+    pub fn serve<F, Fut>(name: &str, handler: F) -> PathBuf
+    where
+        F: Fn(String, UnixStream) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let path = std::env::temp_dir().join(format!("madproxy-{}-{}.sock", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let request = read_request(&mut stream).await;
+                tokio::spawn(handler(request, stream));
+            }
+        });
+        path
+    }
+
+    /// Reads the head of an HTTP request and as much body as it announces.
+    pub async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> String {
+        let mut request = Vec::new();
+        let mut byte = [0u8; 1];
+        while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).await.unwrap() == 1 {
+            request.push(byte[0]);
+        }
+        let head = String::from_utf8_lossy(&request).to_ascii_lowercase();
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .and_then(|length| length.trim().parse().ok())
+            .unwrap_or(0);
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).await.unwrap();
+        request.extend(body);
+        String::from_utf8(request).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        assert_eq!(decode_chunked(b"4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n").unwrap(), b"Wikipedia");
+        assert_eq!(decode_chunked(b"a;name=value\r\n0123456789\r\n0\r\n\r\n").unwrap(), b"0123456789");
+        assert_eq!(decode_chunked(b"0\r\n\r\n").unwrap(), b"");
+        // Chunks may contain the line endings that separate them
+        assert_eq!(decode_chunked(b"4\r\na\r\nb\r\n0\r\n\r\n").unwrap(), b"a\r\nb");
+        assert!(decode_chunked(b"5\r\nWiki\r\n").is_err());
+        assert!(decode_chunked(b"4\r\nWiki\r\n").is_err());
+        assert!(decode_chunked(b"zz\r\nWiki\r\n0\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn decodes_characters_split_across_chunks() {
+        // "é" is 0xC3 0xA9 and "€" is 0xE2 0x82 0xAC
+        let body = b"2\r\na\xC3\r\n3\r\n\xA9\xE2\x82\r\n2\r\n\xACb\r\n0\r\n\r\n";
+        assert_eq!(decode_chunked(body).unwrap(), "aé€b".as_bytes());
+
+        let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        response.extend_from_slice(body);
+        assert_eq!(parse_response(&response).unwrap(), (200, "aé€b".to_string()));
+    }
+
+    #[tokio::test]
+    async fn request_sends_the_body_and_decodes_the_response() {
+        let (requests, mut received) = mpsc::unbounded_channel();
+        let socket = fake::serve("request", move |request, mut stream| {
+            requests.send(request).unwrap();
+            async move {
+                let response = "HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n3\r\n{\"a\r\n4\r\n\":1}\r\n0\r\n\r\n";
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let (status, body) = request(&socket, "POST", "/containers/x/start", Some("{}")).await.unwrap();
+        assert_eq!(status, 201);
+        assert_eq!(body, r#"{"a":1}"#);
+        let sent = received.recv().await.unwrap();
+        assert!(sent.starts_with("POST /containers/x/start HTTP/1.1\r\n"), "{}", sent);
+        assert!(sent.contains("Content-Length: 2\r\n"), "{}", sent);
+        assert!(sent.ends_with("\r\n\r\n{}"), "{}", sent);
+    }
+
+    #[tokio::test]
+    async fn request_reads_plain_bodies_until_close() {
+        let socket = fake::serve("plain", |_, mut stream| async move {
+            stream.write_all(b"HTTP/1.1 304 Not Modified\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+        });
+        assert_eq!(request(&socket, "POST", "/", None).await.unwrap(), (304, String::new()));
+    }
+
+    async fn collect_lines(socket: &Path) -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        stream_lines(socket, "/events", |line| lines.push(line.to_string())).await?;
+        Ok(lines)
+    }
+
+    #[tokio::test]
+    async fn stream_lines_reassembles_lines_across_chunks() {
+        let socket = fake::serve("chunked-stream", |request, mut stream| async move {
+            assert!(request.starts_with("GET /events HTTP/1.1\r\n"));
+            let pieces = [
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\n\r\n",
+                "13\r\n{\"status\":\"start\"}\n\r\n",
+                "c\r\n{\"status\":\"d\r\n",
+                "6\r\nie\"}\n\n\r\n",
+                "0\r\n\r\n",
+            ];
+            for piece in pieces {
+                stream.write_all(piece.as_bytes()).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        assert_eq!(collect_lines(&socket).await.unwrap(), [r#"{"status":"start"}"#, r#"{"status":"die"}"#]);
+    }
+
+    #[tokio::test]
+    async fn stream_lines_reads_unchunked_bodies_until_close() {
+        let socket = fake::serve("plain-stream", |_, mut stream| async move {
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\none\ntwo\n").await.unwrap();
+        });
+        assert_eq!(collect_lines(&socket).await.unwrap(), ["one", "two"]);
+    }
+
+    #[tokio::test]
+    async fn stream_lines_fails_on_error_status() {
+        let socket = fake::serve("error-stream", |_, mut stream| async move {
+            stream.write_all(b"HTTP/1.1 500 Internal Server Error\r\n\r\n").await.unwrap();
+        });
+        assert!(collect_lines(&socket).await.is_err());
+    }
+}